@@ -0,0 +1,3 @@
+drop index if exists users_bio_trgm_idx;
+drop index if exists users_display_name_trgm_idx;
+drop index if exists users_username_prefix_idx;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+create extension if not exists pg_trgm;
+
+create index if not exists users_username_prefix_idx on users (lower(username) text_pattern_ops);
+create index if not exists users_display_name_trgm_idx on users using gin (display_name gin_trgm_ops);
+create index if not exists users_bio_trgm_idx on users using gin (bio gin_trgm_ops);
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+create table if not exists task_queue
+(
+    task_id bigserial primary key,
+    task bytea not null,
+    attempts int not null default 0,
+    run_after timestamp not null default now(),
+    leased_until timestamp,
+    created timestamp not null default now()
+);
+
+create index if not exists task_queue_ready_idx on task_queue (run_after, task_id);
@@ -0,0 +1,3 @@
+drop table if exists task_last_run;
+drop index if exists task_queue_pending_key_idx;
+alter table task_queue drop column if exists task_key;
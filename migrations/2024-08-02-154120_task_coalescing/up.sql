@@ -0,0 +1,17 @@
+-- Your SQL goes here
+alter table task_queue add column if not exists task_key text not null default '';
+
+-- tasks queued before keys existed can't be keyed from sql, give each its own key so they aren't coalesced
+update task_queue set task_key = 'unkeyed:' || task_id where task_key = '';
+alter table task_queue alter column task_key drop default;
+
+-- identical tasks that haven't been picked up yet are coalesced into one row
+create unique index if not exists task_queue_pending_key_idx on task_queue (task_key) where leased_until is null;
+
+create table if not exists task_last_run
+(
+    task_key text primary key,
+    last_run timestamp not null
+);
+
+create index if not exists task_last_run_last_run_idx on task_last_run (last_run);
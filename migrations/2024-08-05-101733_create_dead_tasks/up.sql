@@ -0,0 +1,10 @@
+-- Your SQL goes here
+create table if not exists dead_tasks
+(
+    task_id bigint primary key,
+    task_key text not null,
+    task bytea not null,
+    attempts int not null,
+    last_error text not null,
+    failed_at timestamp not null default now()
+);
@@ -0,0 +1,5 @@
+drop index if exists task_queue_ready_idx;
+create index if not exists task_queue_ready_idx on task_queue (run_after, task_id);
+
+alter table task_queue drop column if exists origin;
+alter table task_queue drop column if exists priority;
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- 0 = interactive, 1 = background
+alter table task_queue add column if not exists priority smallint not null default 1;
+alter table task_queue add column if not exists origin text not null default 'crawl';
+
+drop index if exists task_queue_ready_idx;
+create index if not exists task_queue_ready_idx on task_queue (priority, run_after, task_id);
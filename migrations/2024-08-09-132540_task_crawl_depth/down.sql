@@ -0,0 +1,2 @@
+alter table task_queue drop column if exists depth;
+alter table task_queue drop column if exists seed_fid;
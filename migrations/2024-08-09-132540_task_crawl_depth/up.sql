@@ -0,0 +1,3 @@
+-- Your SQL goes here
+alter table task_queue add column if not exists seed_fid bigint not null default 0;
+alter table task_queue add column if not exists depth integer not null default 0;
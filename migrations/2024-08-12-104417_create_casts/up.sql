@@ -0,0 +1,19 @@
+-- Your SQL goes here
+create table if not exists casts
+(
+    hash bytea primary key,
+    fid bigint not null references users (fid),
+    text text not null,
+    parent_hash bytea,
+    parent_fid bigint,
+    parent_url text,
+    -- cbor encoded list of url and cast embeds
+    embeds bytea not null,
+    mentions bigint[] not null default '{}',
+    mentions_positions int[] not null default '{}',
+    timestamp timestamp not null
+);
+
+create index if not exists casts_fid_timestamp_idx on casts (fid, timestamp desc);
+create index if not exists casts_parent_hash_idx on casts (parent_hash) where parent_hash is not null;
+create index if not exists casts_parent_url_idx on casts (parent_url, timestamp desc) where parent_url is not null;
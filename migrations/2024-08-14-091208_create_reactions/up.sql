@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- reaction_type follows the hub's ReactionType, 1 = like, 2 = recast
+create table if not exists reactions
+(
+    fid bigint not null references users (fid),
+    target_hash bytea not null,
+    reaction_type smallint not null,
+    target_fid bigint not null,
+    timestamp timestamp not null,
+    primary key (fid, target_hash, reaction_type)
+);
+
+create index if not exists reactions_fid_timestamp_idx on reactions (fid, reaction_type, timestamp desc);
+create index if not exists reactions_target_idx on reactions (target_hash, reaction_type);
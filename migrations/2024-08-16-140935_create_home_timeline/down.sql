@@ -0,0 +1,2 @@
+drop table if exists timeline_pull_authors;
+drop table if exists home_timeline;
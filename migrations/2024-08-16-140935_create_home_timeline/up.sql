@@ -0,0 +1,25 @@
+-- Your SQL goes here
+-- casts and recasts fanned out to the followers that have signers here.
+-- sort_key is the cast hash, with the recaster's fid appended for recasts
+create table if not exists home_timeline
+(
+    fid bigint not null,
+    sort_key bytea not null,
+    cast_hash bytea not null,
+    author_fid bigint not null,
+    recasted_by bigint,
+    is_reply boolean not null,
+    timestamp timestamp not null,
+    primary key (fid, sort_key)
+);
+
+create index if not exists home_timeline_fid_timestamp_idx on home_timeline (fid, timestamp desc, sort_key desc);
+create index if not exists home_timeline_cast_hash_idx on home_timeline (cast_hash);
+
+-- authors with too many followers to fan out to, their casts are pulled when timelines are read
+create table if not exists timeline_pull_authors
+(
+    fid bigint primary key,
+    followers bigint not null,
+    updated timestamp not null default now()
+);
@@ -0,0 +1 @@
+drop table if exists cast_tombstones;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- what's left of deleted casts, so threads can still be walked through them
+create table if not exists cast_tombstones
+(
+    hash bytea primary key,
+    fid bigint not null,
+    parent_hash bytea,
+    parent_fid bigint,
+    deleted_at timestamp not null default now()
+);
@@ -0,0 +1,2 @@
+drop table if exists channel_follows;
+drop table if exists channels;
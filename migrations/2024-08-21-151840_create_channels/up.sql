@@ -0,0 +1,21 @@
+-- Your SQL goes here
+-- channels are keyed by their short id, casts point at them through parent_url
+create table if not exists channels
+(
+    id text primary key,
+    url text not null unique,
+    name text not null default '',
+    description text not null default '',
+    image_url text,
+    created timestamp not null default now()
+);
+
+create table if not exists channel_follows
+(
+    fid bigint not null references users (fid),
+    channel_id text not null references channels (id) on delete cascade,
+    timestamp timestamp not null default now(),
+    primary key (fid, channel_id)
+);
+
+create index if not exists channel_follows_channel_idx on channel_follows (channel_id);
@@ -0,0 +1,3 @@
+create index if not exists reactions_target_idx on reactions (target_hash, reaction_type);
+drop index if exists reactions_target_timestamp_idx;
+drop table if exists reaction_counts;
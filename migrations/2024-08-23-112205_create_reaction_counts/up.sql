@@ -0,0 +1,20 @@
+-- Your SQL goes here
+-- kept up to date as reactions are stored and removed, targets don't have to be indexed
+create table if not exists reaction_counts
+(
+    hash bytea primary key,
+    likes bigint not null default 0,
+    recasts bigint not null default 0
+);
+
+insert into reaction_counts (hash, likes, recasts)
+select target_hash,
+       count(*) filter (where reaction_type = 1),
+       count(*) filter (where reaction_type = 2)
+from reactions
+group by target_hash
+on conflict do nothing;
+
+-- reactors of a cast, newest first
+create index if not exists reactions_target_timestamp_idx on reactions (target_hash, reaction_type, timestamp desc);
+drop index if exists reactions_target_idx;
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+-- feeds (an fid's casts or recasts, a channel) that a sync has paged all the way through without failing.
+-- Syncs of a feed without a row here don't stop at casts we already have
+create table if not exists feed_backfills
+(
+    feed text primary key,
+    completed timestamp not null default now()
+);
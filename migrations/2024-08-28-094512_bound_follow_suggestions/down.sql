@@ -0,0 +1,21 @@
+drop materialized view if exists follow_suggestions;
+create materialized view follow_suggestions as
+select ranked.fid, ranked.suggested_fid, ranked.overlap
+from (
+    select l1.fid,
+           l2.target as suggested_fid,
+           count(*) as overlap,
+           row_number() over (partition by l1.fid order by count(*) desc, l2.target) as rank
+    from links l1
+    join links l2 on l2.fid = l1.target and l2.link_type = 'follow'
+    where l1.link_type = 'follow'
+      and l2.target <> l1.fid
+      and not exists (
+          select 1 from links l3 where l3.fid = l1.fid and l3.target = l2.target and l3.link_type = 'follow'
+      )
+    group by l1.fid, l2.target
+) ranked
+where ranked.rank <= 200;
+
+create unique index if not exists follow_suggestions_fid_suggested_idx on follow_suggestions (fid, suggested_fid);
+create index if not exists follow_suggestions_fid_overlap_idx on follow_suggestions (fid, overlap desc);
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- latest remove per link, so an add older than it that's applied late doesn't bring the link back
+create table if not exists link_removals
+(
+    fid bigint not null,
+    target bigint not null,
+    link_type text not null,
+    timestamp timestamp not null,
+    primary key (fid, target, link_type)
+);
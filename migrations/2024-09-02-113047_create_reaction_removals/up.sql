@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- latest remove per reaction, so an add older than it that's applied late doesn't bring the reaction back
+create table if not exists reaction_removals
+(
+    fid bigint not null,
+    target_hash bytea not null,
+    reaction_type smallint not null,
+    timestamp timestamp not null,
+    primary key (fid, target_hash, reaction_type)
+);
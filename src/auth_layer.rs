@@ -1,4 +1,5 @@
 use std::any::Any;
+use std::collections::HashSet;
 use std::str::FromStr;
 use std::sync::Arc;
 
@@ -6,6 +7,7 @@ use axum::{Extension, extract::Request, response::IntoResponse};
 use axum::extract::State;
 use axum::http::StatusCode;
 use axum::middleware::{Next};
+use dotenvy::var;
 use fatline_rs::{HASH_LENGTH, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
 use fatline_rs::users::{Profile};
 use tokio::sync::Mutex;
@@ -81,6 +83,34 @@ pub async fn fid_sig_auth_middleware(
     }
 }
 
+/// fids allowed on the admin routes, from the comma separated ADMIN_FIDS
+#[derive(Clone, Debug, Default)]
+pub struct AdminFids(Arc<HashSet<u64>>);
+
+impl AdminFids {
+    pub fn from_env() -> Self {
+        let fids = var("ADMIN_FIDS").unwrap_or_default()
+            .split(',')
+            .filter_map(|fid| u64::from_str(fid.trim()).ok())
+            .collect();
+        AdminFids(Arc::new(fids))
+    }
+}
+
+// has to run inside fid_sig_auth_middleware, which provides the profile
+pub async fn admin_middleware(
+    State(admins): State<AdminFids>,
+    request: Request,
+    next: Next,
+) -> Result<impl IntoResponse, StatusCode> {
+    let profile = request.extensions().get::<Profile>().ok_or(StatusCode::UNAUTHORIZED)?;
+    if !admins.0.contains(&profile.fid) {
+        debug!("fid {} isn't an admin", profile.fid);
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(next.run(request).await)
+}
+
 // Validate that pub_key signed the hash and that pub_key belongs to, and is active on fid
 async fn validate_fid_and_key(
     user_service: &ServiceState,
@@ -0,0 +1,229 @@
+use std::convert::Infallible;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::body::{Body, HttpBody, to_bytes};
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::http::header::{CACHE_CONTROL, CONTENT_LENGTH, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, VARY};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, IntoResponseParts, Response, ResponseParts};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use tracing::error;
+
+use crate::{FID_HEADER, PUB_HEX_HEADER};
+
+// responses are buffered to hash them, anything bigger than this isn't worth caching anyway
+const MAX_CACHED_BODY: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Copy, Clone)]
+pub struct CachePolicy {
+    pub max_age: u32,
+    /// viewer-specific responses must not be stored by shared caches
+    pub private: bool,
+}
+
+impl CachePolicy {
+    pub const fn new(max_age: u32, private: bool) -> Self {
+        CachePolicy { max_age, private }
+    }
+
+    fn cache_control(&self) -> HeaderValue {
+        let scope = if self.private { "private" } else { "public" };
+        HeaderValue::from_str(&format!("{scope}, max-age={}, must-revalidate", self.max_age))
+            .expect("cache-control is always valid ascii")
+    }
+}
+
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// The newest row a response was built from, handlers return it alongside the body to get a Last-Modified header
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LastModified(pub SystemTime);
+
+impl LastModified {
+    /// newest of the unix timestamps (in seconds) a response is built from, if there are any
+    pub fn newest(timestamps: impl IntoIterator<Item = u64>) -> Option<Self> {
+        timestamps.into_iter().max().map(|secs| LastModified(UNIX_EPOCH + Duration::from_secs(secs)))
+    }
+
+    fn http_date(&self) -> String {
+        DateTime::<Utc>::from(self.0).format(HTTP_DATE_FORMAT).to_string()
+    }
+}
+
+impl IntoResponseParts for LastModified {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        res.extensions_mut().insert(self);
+        Ok(res)
+    }
+}
+
+// If-Modified-Since is only consulted without If-None-Match, and compares at the header's second resolution
+fn not_modified_since(headers: &HeaderMap, last_modified: &LastModified) -> bool {
+    if headers.contains_key(IF_NONE_MATCH) {
+        return false;
+    }
+    let since = headers.get(IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| NaiveDateTime::parse_from_str(value, HTTP_DATE_FORMAT).ok());
+    match since {
+        Some(since) => DateTime::<Utc>::from(last_modified.0).timestamp() <= since.and_utc().timestamp(),
+        None => false,
+    }
+}
+
+// bodies that are streamed or too big to hash are passed through without an ETag
+fn too_large_to_buffer(headers: &HeaderMap, body: &Body) -> bool {
+    let content_length = headers.get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    content_length.or(body.size_hint().upper())
+        .map_or(true, |length| length > MAX_CACHED_BODY as u64)
+}
+
+// If-None-Match uses weak comparison, so W/ prefixes are ignored
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers.get_all(IF_NONE_MATCH).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/**
+* Adds Cache-Control and an ETag to successful responses and answers a matching If-None-Match with 304.
+* Handlers that return a [LastModified] also get a Last-Modified header, checked against If-Modified-Since
+* when the request has no If-None-Match. Bodies too large to buffer are passed through without an ETag.
+*/
+pub async fn cache_middleware(
+    State(policy): State<CachePolicy>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let request_headers = request.headers().clone();
+    let response = next.run(request).await;
+
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+
+    parts.headers.insert(CACHE_CONTROL, policy.cache_control());
+    if policy.private {
+        // auth headers identify the viewer
+        let vary = HeaderValue::from_str(&format!("{FID_HEADER}, {PUB_HEX_HEADER}"))
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        parts.headers.insert(VARY, vary);
+    }
+
+    let last_modified = parts.extensions.get::<LastModified>().copied();
+    if let Some(last_modified) = &last_modified {
+        let value = HeaderValue::from_str(&last_modified.http_date())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        parts.headers.insert(LAST_MODIFIED, value);
+        if not_modified_since(&request_headers, last_modified) {
+            return Ok((StatusCode::NOT_MODIFIED, parts.headers).into_response());
+        }
+    }
+
+    if too_large_to_buffer(&parts.headers, &body) {
+        return Ok(Response::from_parts(parts, body));
+    }
+
+    let bytes = to_bytes(body, MAX_CACHED_BODY).await.map_err(|e| {
+        error!("Couldn't buffer response for caching {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // strong etag from the payload itself
+    let etag = format!("\"{}\"", blake3::hash(&bytes).to_hex());
+    let etag_value = HeaderValue::from_str(&etag).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    parts.headers.insert(ETAG, etag_value);
+
+    if etag_matches(&request_headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, parts.headers).into_response());
+    }
+
+    Ok(Response::from_parts(parts, Body::from(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn if_none_match(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(IF_NONE_MATCH, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn etag_matches_exact_weak_and_listed_tags() {
+        let etag = "\"abc\"";
+        assert!(etag_matches(&if_none_match(&["\"abc\""]), etag));
+        assert!(etag_matches(&if_none_match(&["W/\"abc\""]), etag));
+        assert!(etag_matches(&if_none_match(&["\"x\", \"abc\""]), etag));
+        assert!(etag_matches(&if_none_match(&["\"x\"", "\"abc\""]), etag));
+        assert!(etag_matches(&if_none_match(&["*"]), etag));
+    }
+
+    #[test]
+    fn etag_mismatches() {
+        let etag = "\"abc\"";
+        assert!(!etag_matches(&HeaderMap::new(), etag));
+        assert!(!etag_matches(&if_none_match(&["\"abcd\""]), etag));
+        // tags are quoted, a bare value is a different tag
+        assert!(!etag_matches(&if_none_match(&["abc"]), etag));
+    }
+
+    #[test]
+    fn last_modified_is_the_newest_timestamp() {
+        assert_eq!(LastModified::newest([]), None);
+        let newest = LastModified::newest([1_700_000_000, 1_700_000_060, 5]).unwrap();
+        assert_eq!(newest, LastModified(UNIX_EPOCH + Duration::from_secs(1_700_000_060)));
+        assert_eq!(newest.http_date(), "Tue, 14 Nov 2023 22:14:20 GMT");
+    }
+
+    #[test]
+    fn if_modified_since_compares_whole_seconds() {
+        let last_modified = LastModified(UNIX_EPOCH + Duration::from_millis(1_700_000_060_500));
+        let mut headers = HeaderMap::new();
+        assert!(!not_modified_since(&headers, &last_modified));
+        headers.insert(IF_MODIFIED_SINCE, HeaderValue::from_static("Tue, 14 Nov 2023 22:14:20 GMT"));
+        assert!(not_modified_since(&headers, &last_modified));
+        headers.insert(IF_MODIFIED_SINCE, HeaderValue::from_static("Tue, 14 Nov 2023 22:14:19 GMT"));
+        assert!(!not_modified_since(&headers, &last_modified));
+        headers.insert(IF_MODIFIED_SINCE, HeaderValue::from_static("not a date"));
+        assert!(!not_modified_since(&headers, &last_modified));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_if_modified_since() {
+        let last_modified = LastModified(UNIX_EPOCH);
+        let mut headers = if_none_match(&["\"abc\""]);
+        headers.insert(IF_MODIFIED_SINCE, HeaderValue::from_static("Tue, 14 Nov 2023 22:14:20 GMT"));
+        assert!(!not_modified_since(&headers, &last_modified));
+    }
+
+    #[test]
+    fn large_bodies_are_not_buffered() {
+        let headers = HeaderMap::new();
+        assert!(!too_large_to_buffer(&headers, &Body::from(vec![0u8; 1024])));
+        assert!(too_large_to_buffer(&headers, &Body::from(vec![0u8; MAX_CACHED_BODY + 1])));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(MAX_CACHED_BODY + 1));
+        assert!(too_large_to_buffer(&headers, &Body::empty()));
+    }
+
+    #[test]
+    fn cache_control_scope() {
+        assert_eq!(CachePolicy::new(60, false).cache_control(), "public, max-age=60, must-revalidate");
+        assert_eq!(CachePolicy::new(0, true).cache_control(), "private, max-age=0, must-revalidate");
+    }
+}
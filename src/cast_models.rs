@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use diesel::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::error;
+
+use crate::render::TextSegment;
+use crate::user_models::fc_time;
+
+// hashes go over the wire as hex rather than arrays of numbers
+pub(crate) mod hex_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        hex::decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Something attached to a cast, either a url or another cast
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum CastEmbed {
+    Url(String),
+    Cast {
+        fid: u64,
+        #[serde(with = "hex_bytes")]
+        hash: Vec<u8>,
+    },
+}
+
+#[derive(Queryable, QueryableByName, Selectable, Insertable, Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone)]
+#[diesel(table_name=crate::schema::casts)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct StoredCast {
+    pub hash: Vec<u8>,
+    pub fid: i64,
+    pub text: String,
+    pub parent_hash: Option<Vec<u8>>,
+    pub parent_fid: Option<i64>,
+    pub parent_url: Option<String>,
+    pub embeds: Vec<u8>,
+    pub mentions: Vec<i64>,
+    /// byte offsets into `text` where each of `mentions` goes
+    pub mentions_positions: Vec<i32>,
+    pub timestamp: SystemTime,
+}
+
+pub fn encode_embeds(embeds: &[CastEmbed]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    // writing to a vec can't fail
+    ciborium::into_writer(embeds, &mut bytes).expect("Couldn't encode embeds");
+    bytes
+}
+
+impl StoredCast {
+    pub fn embeds(&self) -> Vec<CastEmbed> {
+        ciborium::from_reader(self.embeds.as_slice()).unwrap_or_else(|e| {
+            error!("Couldn't decode embeds for cast {} {e}", hex::encode(&self.hash));
+            vec![]
+        })
+    }
+
+    pub fn is_reply(&self) -> bool {
+        self.parent_hash.is_some()
+    }
+}
+
+/// Where a deleted cast sat in its thread
+#[derive(Queryable, Selectable, Insertable, Debug, Clone)]
+#[diesel(table_name=crate::schema::cast_tombstones)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CastTombstone {
+    pub hash: Vec<u8>,
+    pub fid: i64,
+    pub parent_hash: Option<Vec<u8>>,
+    pub parent_fid: Option<i64>,
+    pub deleted_at: SystemTime,
+}
+
+impl From<&StoredCast> for CastTombstone {
+    fn from(cast: &StoredCast) -> Self {
+        CastTombstone {
+            hash: cast.hash.clone(),
+            fid: cast.fid,
+            parent_hash: cast.parent_hash.clone(),
+            parent_fid: cast.parent_fid,
+            deleted_at: SystemTime::now(),
+        }
+    }
+}
+
+/// A cast add or remove, as seen in hub messages
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone)]
+pub struct CastEvent {
+    pub fid: u64,
+    pub hash: Vec<u8>,
+    pub removed: bool,
+    /// the added cast, stored as is rather than fetched again
+    pub cast: Option<StoredCast>,
+}
+
+/// A cast as returned to clients
+#[derive(Serialize, Debug, Clone)]
+pub struct CastView {
+    #[serde(serialize_with = "hex_bytes::serialize")]
+    pub hash: Vec<u8>,
+    pub fid: u64,
+    pub text: String,
+    #[serde(serialize_with = "serialize_opt_hex")]
+    pub parent_hash: Option<Vec<u8>>,
+    pub parent_fid: Option<u64>,
+    pub parent_url: Option<String>,
+    pub embeds: Vec<CastEmbed>,
+    pub mentions: Vec<u64>,
+    pub mentions_positions: Vec<u32>,
+    /// `text` with the mentions spliced in
+    pub segments: Vec<TextSegment>,
+    /// `segments` as html or markdown, when asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rendered: Option<String>,
+    /// unix seconds
+    pub timestamp: u64,
+    pub reactions: ReactionCounts,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub viewer_liked: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub viewer_recasted: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub viewer_follows_author: Option<bool>,
+}
+
+/// How the authenticated user relates to a cast
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct CastViewerContext {
+    pub liked: bool,
+    pub recasted: bool,
+    pub follows_author: bool,
+}
+
+impl CastView {
+    pub fn set_viewer_context(&mut self, context: CastViewerContext) {
+        self.viewer_liked = Some(context.liked);
+        self.viewer_recasted = Some(context.recasted);
+        self.viewer_follows_author = Some(context.follows_author);
+    }
+
+    /// fills in reaction counts loaded separately, casts missing from `counts` have none
+    pub fn with_reactions(mut self, counts: &HashMap<Vec<u8>, ReactionCounts>) -> Self {
+        self.reactions = counts.get(&self.hash).copied().unwrap_or_default();
+        self
+    }
+}
+
+pub(crate) fn serialize_opt_hex<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match bytes {
+        Some(bytes) => hex_bytes::serialize(bytes, serializer),
+        None => serializer.serialize_none()
+    }
+}
+
+impl From<StoredCast> for CastView {
+    fn from(cast: StoredCast) -> Self {
+        CastView {
+            embeds: cast.embeds(),
+            hash: cast.hash,
+            fid: cast.fid as u64,
+            text: cast.text,
+            parent_hash: cast.parent_hash,
+            parent_fid: cast.parent_fid.map(|fid| fid as u64),
+            parent_url: cast.parent_url,
+            mentions: cast.mentions.into_iter().map(|fid| fid as u64).collect(),
+            mentions_positions: cast.mentions_positions.into_iter().map(|position| position as u32).collect(),
+            segments: vec![],
+            rendered: None,
+            timestamp: cast.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            reactions: ReactionCounts::default(),
+            viewer_liked: None,
+            viewer_recasted: None,
+            viewer_follows_author: None,
+        }
+    }
+}
+
+/// Reaction types, numbered as in the hub's ReactionType
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ReactionKind {
+    Like,
+    Recast,
+}
+
+impl ReactionKind {
+    pub fn code(&self) -> i16 {
+        match self {
+            ReactionKind::Like => 1,
+            ReactionKind::Recast => 2,
+        }
+    }
+
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            1 => Some(ReactionKind::Like),
+            2 => Some(ReactionKind::Recast),
+            _ => None
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone)]
+#[diesel(table_name=crate::schema::reactions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Reaction {
+    pub fid: i64,
+    pub target_hash: Vec<u8>,
+    pub reaction_type: i16,
+    pub target_fid: i64,
+    pub timestamp: SystemTime,
+}
+
+/// A reaction add or remove on a cast, as seen in hub messages
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone)]
+pub struct ReactionEvent {
+    pub fid: u64,
+    pub target_fid: u64,
+    pub target_hash: Vec<u8>,
+    pub reaction_type: i16,
+    pub timestamp: u32,
+    pub removed: bool,
+}
+
+impl ReactionEvent {
+    pub fn to_reaction(&self) -> Reaction {
+        Reaction {
+            fid: self.fid as i64,
+            target_hash: self.target_hash.clone(),
+            reaction_type: self.reaction_type,
+            target_fid: self.target_fid as i64,
+            timestamp: fc_time(self.timestamp),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ReactionCounts {
+    pub likes: u64,
+    pub recasts: u64,
+}
+
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name=crate::schema::reaction_counts)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ReactionCountRow {
+    pub hash: Vec<u8>,
+    pub likes: i64,
+    pub recasts: i64,
+}
+
+impl From<ReactionCountRow> for ReactionCounts {
+    fn from(row: ReactionCountRow) -> Self {
+        ReactionCounts {
+            likes: row.likes.max(0) as u64,
+            recasts: row.recasts.max(0) as u64,
+        }
+    }
+}
+
+/// A channel, the casts in it have its url as their parent_url
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Serialize, Debug, Clone)]
+#[diesel(table_name=crate::schema::channels)]
+#[diesel(primary_key(id))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Channel {
+    pub id: String,
+    pub url: String,
+    pub name: String,
+    pub description: String,
+    pub image_url: Option<String>,
+}
@@ -0,0 +1,856 @@
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+use axum::async_trait;
+use diesel::prelude::*;
+use diesel::PgConnection;
+use diesel::dsl::exists;
+use diesel::sql_types::{Array, Bool, Bytea, Int2, Int8, Timestamp};
+use eyre::{OptionExt, Result};
+use fatline_rs::proto::{CastId, CastsByParentRequest, FidRequest, Message, MessageType, ReactionsByFidRequest, ReactionsByTargetRequest, ReactionType};
+use fatline_rs::proto::casts_by_parent_request::Parent as ParentQuery;
+use fatline_rs::proto::cast_add_body::Parent;
+use fatline_rs::proto::embed::Embed as EmbedKind;
+use fatline_rs::proto::message_data::Body as MBody;
+use fatline_rs::proto::reaction_body::Target as ReactionTarget;
+use fatline_rs::proto::reactions_by_target_request::Target as ReactionsTarget;
+use tracing::debug;
+
+use fatline_rs::users::Profile;
+use crate::cast_models::{CastEmbed, CastEvent, CastTombstone, CastView, CastViewerContext, encode_embeds, Reaction, ReactionCountRow, ReactionCounts, ReactionEvent, ReactionKind, StoredCast};
+use crate::pagination::Cursor;
+use crate::schema::casts::dsl::{casts, fid as c_fid, hash};
+use crate::schema::reactions::dsl::{fid as r_fid, reaction_type, reactions, target_hash};
+use crate::schema::users::dsl::users;
+use crate::service::ServiceState;
+use crate::timeline_repo::{fan_out_casts, fan_out_recasts, remove_from_timelines, remove_recast_from_timelines};
+use crate::user_models::{fc_time, User};
+
+const CAST_PAGE_SIZE: u32 = 100;
+// keeps multi-row inserts well under postgres' bind parameter limit
+const INSERT_CHUNK_SIZE: usize = 1000;
+
+#[async_trait]
+pub trait CastRepository {
+    /**
+    * pages through fid_q's casts on the hub, newest first, and stores them.
+    * Unforced runs stop at the first page with casts we already have once a run has gone all the way through,
+    * forced runs re-sync everything and drop casts the hub no longer has. Returns how many casts were seen.
+    */
+    async fn fetch_and_store_casts(&self, fid_q: u64, force: bool) -> Result<usize>;
+    async fn get_cast(&self, hash_q: Vec<u8>) -> Result<Option<StoredCast>>;
+    async fn fetch_and_store_cast(&self, fid_q: u64, hash_q: Vec<u8>) -> Result<StoredCast>;
+    /// stores a cast we already have the message for, returns false if it was already stored
+    async fn store_cast(&self, cast: StoredCast) -> Result<bool>;
+    /// direct replies to a cast from the hub, oldest first, stopping after `limit`
+    async fn fetch_and_store_replies(&self, fid_q: u64, hash_q: Vec<u8>, limit: usize) -> Result<Vec<StoredCast>>;
+    /**
+    * stores fid_q's recasts the same way as fetch_and_store_casts,
+    * returns the recasted casts we don't have yet as (fid, hash)
+    */
+    async fn fetch_and_store_recasts(&self, fid_q: u64, force: bool) -> Result<Vec<(u64, Vec<u8>)>>;
+    /// syncs the likes and recasts on a cast, all of them the first time and then until a known one. Returns how many were new
+    async fn fetch_and_store_cast_reactions(&self, fid_q: u64, hash_q: Vec<u8>) -> Result<usize>;
+    /**
+    * newest casts posted to a channel on the hub, stopping at `limit`, or at the first page with casts we
+    * already have once a run has reached `limit`. Returns how many were seen.
+    */
+    async fn fetch_and_store_channel_casts(&self, url: String, limit: usize) -> Result<usize>;
+    /// applies a streamed reaction add or remove, returns false if it changed nothing
+    async fn store_reaction_event(&self, event: ReactionEvent) -> Result<bool>;
+    async fn get_reaction_counts(&self, hashes: Vec<Vec<u8>>) -> Result<HashMap<Vec<u8>, ReactionCounts>>;
+    /// who reacted to a cast with `kind`, most recent first
+    async fn get_reactors(&self, hash_q: Vec<u8>, kind: ReactionKind, limit: i64, cursor: Option<Cursor>) -> Result<(Vec<Profile>, Option<Cursor>)>;
+    /// fills in how viewer relates to every cast on a page, in one query
+    async fn add_viewer_context(&self, viewer: u64, cast_views: Vec<&mut CastView>) -> Result<()>;
+    /// removes a deleted cast from our index and every timeline it was fanned out to.
+    /// It's buried even if it isn't stored yet, so a late add can't bring it back
+    async fn delete_cast(&self, fid_q: u64, hash_q: Vec<u8>) -> Result<()>;
+}
+
+pub fn cast_from_message(message: &Message) -> Option<StoredCast> {
+    let data = message.data.as_ref()?;
+    if data.r#type() != MessageType::CastAdd {
+        return None;
+    }
+    let Some(MBody::CastAddBody(body)) = &data.body else { return None };
+
+    let (parent_hash, parent_fid, parent_url) = match &body.parent {
+        Some(Parent::ParentCastId(cast_id)) => (Some(cast_id.hash.clone()), Some(cast_id.fid as i64), None),
+        Some(Parent::ParentUrl(url)) => (None, None, Some(url.clone())),
+        None => (None, None, None)
+    };
+    let embeds = body.embeds.iter().filter_map(|embed| match &embed.embed {
+        Some(EmbedKind::Url(url)) => Some(CastEmbed::Url(url.clone())),
+        Some(EmbedKind::CastId(cast_id)) => Some(CastEmbed::Cast { fid: cast_id.fid, hash: cast_id.hash.clone() }),
+        None => None
+    }).collect::<Vec<_>>();
+
+    Some(StoredCast {
+        hash: message.hash.clone(),
+        fid: data.fid as i64,
+        text: body.text.clone(),
+        parent_hash,
+        parent_fid,
+        parent_url,
+        embeds: encode_embeds(&embeds),
+        mentions: body.mentions.iter().map(|mention| *mention as i64).collect(),
+        mentions_positions: body.mentions_positions.iter().map(|position| *position as i32).collect(),
+        timestamp: fc_time(data.timestamp),
+    })
+}
+
+/// parses a ReactionAdd / ReactionRemove on a cast, reactions to urls aren't stored
+pub fn reaction_event_from_message(message: &Message) -> Option<ReactionEvent> {
+    let data = message.data.as_ref()?;
+    let removed = match data.r#type() {
+        MessageType::ReactionAdd => false,
+        MessageType::ReactionRemove => true,
+        _ => return None
+    };
+    let Some(MBody::ReactionBody(body)) = &data.body else { return None };
+    let kind = ReactionKind::from_code(body.r#type)?;
+    match &body.target {
+        Some(ReactionTarget::TargetCastId(cast_id)) => Some(ReactionEvent {
+            fid: data.fid,
+            target_fid: cast_id.fid,
+            target_hash: cast_id.hash.clone(),
+            reaction_type: kind.code(),
+            timestamp: data.timestamp,
+            removed,
+        }),
+        _ => None
+    }
+}
+
+/// parses a ReactionAdd on a cast
+pub fn reaction_from_message(message: &Message) -> Option<Reaction> {
+    reaction_event_from_message(message)
+        .filter(|event| !event.removed)
+        .map(|event| event.to_reaction())
+}
+
+/// parses a CastAdd / CastRemove, adds carry the whole cast
+pub fn cast_event_from_message(message: &Message) -> Option<CastEvent> {
+    let data = message.data.as_ref()?;
+    match &data.body {
+        Some(MBody::CastAddBody(_)) => Some(CastEvent {
+            fid: data.fid,
+            hash: message.hash.clone(),
+            removed: false,
+            cast: Some(cast_from_message(message)?),
+        }),
+        Some(MBody::CastRemoveBody(body)) => Some(CastEvent {
+            fid: data.fid,
+            hash: body.target_hash.clone(),
+            removed: true,
+            cast: None,
+        }),
+        _ => None
+    }
+}
+
+// leaves tombstones for deleted casts so their threads stay connected, and pulls them from timelines
+fn bury_casts(db: &mut PgConnection, removed: &[StoredCast]) -> QueryResult<()> {
+    use crate::schema::cast_tombstones::dsl::cast_tombstones;
+
+    let tombstones = removed.iter().map(CastTombstone::from).collect::<Vec<_>>();
+    for chunk in tombstones.chunks(INSERT_CHUNK_SIZE) {
+        diesel::insert_into(cast_tombstones)
+            .values(chunk)
+            .on_conflict_do_nothing()
+            .execute(db)?;
+    }
+    for cast in removed {
+        remove_from_timelines(db, &cast.hash)?;
+    }
+    Ok(())
+}
+
+// adds each target's deltas to its counts, a target we haven't counted yet starts from zero
+const ADJUST_COUNTS_QUERY: &'static str = r#"
+WITH changed AS (
+    SELECT * FROM unnest($1::bytea[], $2::int8[], $3::int8[]) AS changed(hash, likes, recasts)
+)
+INSERT INTO reaction_counts (hash, likes, recasts)
+SELECT hash, greatest(likes, 0), greatest(recasts, 0) FROM changed
+ON CONFLICT (hash) DO UPDATE SET
+    likes = greatest(reaction_counts.likes + (SELECT likes FROM changed WHERE changed.hash = excluded.hash), 0),
+    recasts = greatest(reaction_counts.recasts + (SELECT recasts FROM changed WHERE changed.hash = excluded.hash), 0)
+"#;
+
+fn adjust_reaction_counts(db: &mut PgConnection, changed: &[Reaction], sign: i64) -> QueryResult<()> {
+    let mut deltas = HashMap::<&[u8], (i64, i64)>::new();
+    for reaction in changed {
+        let delta = deltas.entry(&reaction.target_hash).or_default();
+        match ReactionKind::from_code(reaction.reaction_type as i32) {
+            Some(ReactionKind::Like) => delta.0 += sign,
+            Some(ReactionKind::Recast) => delta.1 += sign,
+            None => {}
+        }
+    }
+    if deltas.is_empty() {
+        return Ok(());
+    }
+    let (hashes, likes, recasts) = deltas.into_iter()
+        .fold((vec![], vec![], vec![]), |(mut hashes, mut likes, mut recasts), (target, (like, recast))| {
+            hashes.push(target.to_vec());
+            likes.push(like);
+            recasts.push(recast);
+            (hashes, likes, recasts)
+        });
+    diesel::sql_query(ADJUST_COUNTS_QUERY)
+        .bind::<Array<Bytea>, _>(hashes)
+        .bind::<Array<Int8>, _>(likes)
+        .bind::<Array<Int8>, _>(recasts)
+        .execute(db)?;
+    Ok(())
+}
+
+/// reaction counts for the given casts, casts nobody reacted to are left out
+pub(crate) fn load_reaction_counts(db: &mut PgConnection, hashes: &[Vec<u8>]) -> QueryResult<HashMap<Vec<u8>, ReactionCounts>> {
+    use crate::schema::reaction_counts::dsl::{hash as rc_hash, reaction_counts};
+
+    Ok(reaction_counts.select(ReactionCountRow::as_select())
+        .filter(rc_hash.eq_any(hashes))
+        .load(db)?
+        .into_iter()
+        .map(|row| (row.hash.clone(), row.into()))
+        .collect())
+}
+
+// one row per cast on a page, everything the viewer's done to it
+const CAST_VIEWER_CONTEXT_QUERY: &'static str = r#"
+SELECT c.hash,
+       EXISTS (SELECT 1 FROM reactions r WHERE r.fid = $1 AND r.target_hash = c.hash AND r.reaction_type = 1) AS liked,
+       EXISTS (SELECT 1 FROM reactions r WHERE r.fid = $1 AND r.target_hash = c.hash AND r.reaction_type = 2) AS recasted,
+       EXISTS (SELECT 1 FROM links l WHERE l.fid = $1 AND l.target = c.fid AND l.link_type = 'follow') AS follows_author
+FROM unnest($2::bytea[], $3::int8[]) AS c(hash, fid)
+"#;
+
+#[derive(QueryableByName, Debug)]
+struct CastViewerRow {
+    #[diesel(sql_type = Bytea)]
+    hash: Vec<u8>,
+    #[diesel(sql_type = Bool)]
+    liked: bool,
+    #[diesel(sql_type = Bool)]
+    recasted: bool,
+    #[diesel(sql_type = Bool)]
+    follows_author: bool,
+}
+
+// un-counts removed reactions and takes undone recasts back out of timelines
+fn forget_reactions(db: &mut PgConnection, removed: &[Reaction]) -> QueryResult<()> {
+    adjust_reaction_counts(db, removed, -1)?;
+    for reaction in removed.iter().filter(|reaction| reaction.reaction_type == ReactionKind::Recast.code()) {
+        remove_recast_from_timelines(db, reaction.fid, &reaction.target_hash)?;
+    }
+    Ok(())
+}
+
+// keeps the newest remove for a reaction
+const STORE_REACTION_REMOVAL_QUERY: &'static str = r#"
+INSERT INTO reaction_removals (fid, target_hash, reaction_type, timestamp) VALUES ($1, $2, $3, $4)
+ON CONFLICT (fid, target_hash, reaction_type) DO UPDATE SET timestamp = excluded.timestamp
+WHERE reaction_removals.timestamp < excluded.timestamp
+"#;
+
+// stores reactions and the fids on both ends, returns how many were new
+pub(crate) fn upsert_reactions(db: &mut PgConnection, new_reactions: &[Reaction]) -> QueryResult<usize> {
+    let fids = new_reactions.iter()
+        .flat_map(|reaction| [reaction.fid, reaction.target_fid])
+        .collect::<HashSet<_>>();
+    db.transaction(|db| {
+        diesel::insert_into(users)
+            .values(fids.into_iter().map(User::empty).collect::<Vec<_>>())
+            .on_conflict_do_nothing()
+            .execute(db)?;
+        let mut inserted = 0;
+        for chunk in new_reactions.chunks(INSERT_CHUNK_SIZE) {
+            let stored = diesel::insert_into(reactions)
+                .values(chunk)
+                .on_conflict_do_nothing()
+                .returning(Reaction::as_returning())
+                .get_results(db)?;
+            adjust_reaction_counts(db, &stored, 1)?;
+            fan_out_recasts(db, &stored)?;
+            inserted += stored.len();
+        }
+        Ok(inserted)
+    })
+}
+
+/*
+* takes the mark a finished sync left on a feed, so a sync that fails partway leaves it unmarked
+* and the next one pages past casts it already has to fill the gap
+*/
+fn take_backfilled(db: &mut PgConnection, feed_q: &str) -> QueryResult<bool> {
+    use crate::schema::feed_backfills::dsl::{feed, feed_backfills};
+
+    Ok(diesel::delete(feed_backfills.filter(feed.eq(feed_q))).execute(db)? > 0)
+}
+
+fn mark_backfilled(db: &mut PgConnection, feed_q: &str) -> QueryResult<()> {
+    use crate::schema::feed_backfills::dsl::{completed, feed, feed_backfills};
+
+    diesel::insert_into(feed_backfills)
+        .values((feed.eq(feed_q), completed.eq(SystemTime::now())))
+        .on_conflict(feed)
+        .do_update()
+        .set(completed.eq(SystemTime::now()))
+        .execute(db)?;
+    Ok(())
+}
+
+// stores casts and their authors, returns how many were new. Deleted casts are never stored again
+pub(crate) fn upsert_casts(db: &mut PgConnection, new_casts: &[StoredCast]) -> QueryResult<usize> {
+    use crate::schema::cast_tombstones::dsl::{cast_tombstones, hash as t_hash};
+
+    let authors = new_casts.iter().map(|cast| cast.fid).collect::<HashSet<_>>();
+    db.transaction(|db| {
+        diesel::insert_into(users)
+            .values(authors.into_iter().map(User::empty).collect::<Vec<_>>())
+            .on_conflict_do_nothing()
+            .execute(db)?;
+        let mut inserted = 0;
+        for chunk in new_casts.chunks(INSERT_CHUNK_SIZE) {
+            // a remove can be applied before its add, the tombstone it left wins
+            let hashes = chunk.iter().map(|cast| cast.hash.clone()).collect::<Vec<_>>();
+            let buried = cast_tombstones.select(t_hash)
+                .filter(t_hash.eq_any(&hashes))
+                .load::<Vec<u8>>(db)?
+                .into_iter()
+                .collect::<HashSet<_>>();
+            let live = chunk.iter().filter(|cast| !buried.contains(&cast.hash)).cloned().collect::<Vec<_>>();
+            if live.is_empty() {
+                continue;
+            }
+            // casts can't be edited, so an existing row is already up to date and already fanned out
+            let stored = diesel::insert_into(casts)
+                .values(live.as_slice())
+                .on_conflict_do_nothing()
+                .returning(StoredCast::as_returning())
+                .get_results(db)?;
+            fan_out_casts(db, &stored)?;
+            inserted += stored.len();
+        }
+        Ok(inserted)
+    })
+}
+
+#[async_trait]
+impl CastRepository for ServiceState {
+    async fn fetch_and_store_casts(&self, fid_q: u64, force: bool) -> Result<usize> {
+        let mut db = self.db_pool.get()?;
+        let mut hub_client = {
+            let locked = self.hub_client.lock().await;
+            locked.clone()
+        };
+
+        let feed = format!("casts:{fid_q}");
+        let stop_at_known = take_backfilled(&mut db, &feed)? && !force;
+        let mut seen = Vec::new();
+        let mut page_token = None;
+        loop {
+            let response = hub_client.get_casts_by_fid(FidRequest {
+                fid: fid_q,
+                page_size: Some(CAST_PAGE_SIZE),
+                page_token: page_token.take(),
+                reverse: Some(true),
+            }).await?.into_inner();
+
+            let page = response.messages.iter().filter_map(cast_from_message).collect::<Vec<_>>();
+            seen.extend(page.iter().map(|cast| cast.hash.clone()));
+            let inserted = upsert_casts(&mut db, &page)?;
+
+            // everything older than a cast we already had was indexed by an earlier run that finished
+            if stop_at_known && inserted < page.len() {
+                break;
+            }
+            match response.next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => break
+            }
+        }
+        mark_backfilled(&mut db, &feed)?;
+
+        if force {
+            // a full run saw every cast the hub still has
+            let removed = db.transaction::<_, eyre::Error, _>(|db| {
+                let removed = diesel::delete(casts.filter(c_fid.eq(fid_q as i64).and(hash.ne_all(&seen))))
+                    .returning(StoredCast::as_returning())
+                    .get_results(db)?;
+                bury_casts(db, &removed)?;
+                Ok(removed.len())
+            })?;
+            debug!("Removed {removed} casts {fid_q} deleted");
+        }
+        Ok(seen.len())
+    }
+
+    async fn get_cast(&self, hash_q: Vec<u8>) -> Result<Option<StoredCast>> {
+        let mut db = self.db_pool.get()?;
+        Ok(casts.select(StoredCast::as_select())
+            .filter(hash.eq(hash_q))
+            .get_result(&mut db)
+            .optional()?)
+    }
+
+    async fn fetch_and_store_cast(&self, fid_q: u64, hash_q: Vec<u8>) -> Result<StoredCast> {
+        let mut db = self.db_pool.get()?;
+        let mut hub_client = {
+            let locked = self.hub_client.lock().await;
+            locked.clone()
+        };
+        let message = hub_client.get_cast(CastId {
+            fid: fid_q,
+            hash: hash_q,
+        }).await?.into_inner();
+        let cast = cast_from_message(&message).ok_or_eyre("hub returned something other than a cast")?;
+        upsert_casts(&mut db, std::slice::from_ref(&cast))?;
+        Ok(cast)
+    }
+
+    async fn store_cast(&self, cast: StoredCast) -> Result<bool> {
+        let mut db = self.db_pool.get()?;
+        Ok(upsert_casts(&mut db, std::slice::from_ref(&cast))? > 0)
+    }
+
+    async fn fetch_and_store_replies(&self, fid_q: u64, hash_q: Vec<u8>, limit: usize) -> Result<Vec<StoredCast>> {
+        let mut db = self.db_pool.get()?;
+        let mut hub_client = {
+            let locked = self.hub_client.lock().await;
+            locked.clone()
+        };
+
+        let mut replies = Vec::new();
+        let mut page_token = None;
+        while replies.len() < limit {
+            let response = hub_client.get_casts_by_parent(CastsByParentRequest {
+                parent: Some(ParentQuery::ParentCastId(CastId {
+                    fid: fid_q,
+                    hash: hash_q.clone(),
+                })),
+                page_size: Some(CAST_PAGE_SIZE.min(limit as u32)),
+                page_token: page_token.take(),
+                reverse: None,
+            }).await?.into_inner();
+
+            let mut page = response.messages.iter().filter_map(cast_from_message).collect::<Vec<_>>();
+            page.truncate(limit - replies.len());
+            upsert_casts(&mut db, &page)?;
+            replies.extend(page);
+
+            match response.next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => break
+            }
+        }
+        Ok(replies)
+    }
+
+    async fn fetch_and_store_recasts(&self, fid_q: u64, force: bool) -> Result<Vec<(u64, Vec<u8>)>> {
+        let mut db = self.db_pool.get()?;
+        let mut hub_client = {
+            let locked = self.hub_client.lock().await;
+            locked.clone()
+        };
+
+        let feed = format!("recasts:{fid_q}");
+        let stop_at_known = take_backfilled(&mut db, &feed)? && !force;
+        let mut seen = Vec::new();
+        let mut targets = Vec::new();
+        let mut page_token = None;
+        loop {
+            let response = hub_client.get_reactions_by_fid(ReactionsByFidRequest {
+                fid: fid_q,
+                reaction_type: Some(ReactionType::Recast as i32),
+                page_size: Some(CAST_PAGE_SIZE),
+                page_token: page_token.take(),
+                reverse: Some(true),
+            }).await?.into_inner();
+
+            let page = response.messages.iter().filter_map(reaction_from_message).collect::<Vec<_>>();
+            seen.extend(page.iter().map(|recast| recast.target_hash.clone()));
+            targets.extend(page.iter().map(|recast| (recast.target_fid as u64, recast.target_hash.clone())));
+            let inserted = upsert_reactions(&mut db, &page)?;
+
+            if stop_at_known && inserted < page.len() {
+                break;
+            }
+            match response.next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => break
+            }
+        }
+        mark_backfilled(&mut db, &feed)?;
+
+        if force {
+            // undone recasts are gone from the hub
+            db.transaction::<_, eyre::Error, _>(|db| {
+                let undone = diesel::delete(reactions.filter(
+                    r_fid.eq(fid_q as i64)
+                        .and(reaction_type.eq(ReactionKind::Recast.code()))
+                        .and(target_hash.ne_all(&seen))
+                )).returning(Reaction::as_returning()).get_results(db)?;
+                forget_reactions(db, &undone)?;
+                Ok(())
+            })?;
+        }
+
+        let stored = casts.select(hash)
+            .filter(hash.eq_any(&seen))
+            .load::<Vec<u8>>(&mut db)?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        Ok(targets.into_iter().filter(|(_, target)| !stored.contains(target)).collect())
+    }
+
+    async fn fetch_and_store_cast_reactions(&self, fid_q: u64, hash_q: Vec<u8>) -> Result<usize> {
+        let mut db = self.db_pool.get()?;
+        let mut hub_client = {
+            let locked = self.hub_client.lock().await;
+            locked.clone()
+        };
+
+        // the first full sync seeds the cast's counts, later ones only pick up what the subscriber missed
+        let feed = format!("reactions:{}", hex::encode(&hash_q));
+        let stop_at_known = take_backfilled(&mut db, &feed)?;
+        let mut stored = 0;
+        let mut page_token = None;
+        loop {
+            let response = hub_client.get_reactions_by_cast(ReactionsByTargetRequest {
+                target: Some(ReactionsTarget::TargetCastId(CastId {
+                    fid: fid_q,
+                    hash: hash_q.clone(),
+                })),
+                reaction_type: None,
+                page_size: Some(CAST_PAGE_SIZE),
+                page_token: page_token.take(),
+                reverse: Some(true),
+            }).await?.into_inner();
+
+            let page = response.messages.iter().filter_map(reaction_from_message).collect::<Vec<_>>();
+            let inserted = upsert_reactions(&mut db, &page)?;
+            stored += inserted;
+
+            if stop_at_known && inserted < page.len() {
+                break;
+            }
+            match response.next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => break
+            }
+        }
+        mark_backfilled(&mut db, &feed)?;
+        Ok(stored)
+    }
+
+    async fn fetch_and_store_channel_casts(&self, url: String, limit: usize) -> Result<usize> {
+        let mut db = self.db_pool.get()?;
+        let mut hub_client = {
+            let locked = self.hub_client.lock().await;
+            locked.clone()
+        };
+
+        let feed = format!("channel:{url}");
+        let stop_at_known = take_backfilled(&mut db, &feed)?;
+        let mut seen = 0;
+        let mut page_token = None;
+        while seen < limit {
+            let response = hub_client.get_casts_by_parent(CastsByParentRequest {
+                parent: Some(ParentQuery::ParentUrl(url.clone())),
+                page_size: Some(CAST_PAGE_SIZE.min(limit as u32)),
+                page_token: page_token.take(),
+                reverse: Some(true),
+            }).await?.into_inner();
+
+            let page = response.messages.iter().filter_map(cast_from_message).collect::<Vec<_>>();
+            seen += page.len();
+            let inserted = upsert_casts(&mut db, &page)?;
+
+            // everything older was stored by an earlier run that finished
+            if stop_at_known && inserted < page.len() {
+                break;
+            }
+            match response.next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => break
+            }
+        }
+        mark_backfilled(&mut db, &feed)?;
+        Ok(seen)
+    }
+
+    async fn store_reaction_event(&self, event: ReactionEvent) -> Result<bool> {
+        use crate::schema::reaction_removals::dsl::{reaction_removals, timestamp as removed_at};
+        use crate::schema::reactions::dsl::timestamp as r_timestamp;
+
+        let mut db = self.db_pool.get()?;
+        let reaction = event.to_reaction();
+        let key = (reaction.fid, reaction.target_hash.clone(), reaction.reaction_type);
+        // events can be applied out of order, the newest message for a reaction wins and a remove wins a tie
+        let changed = db.transaction::<_, diesel::result::Error, _>(|db| {
+            if event.removed {
+                diesel::sql_query(STORE_REACTION_REMOVAL_QUERY)
+                    .bind::<Int8, _>(reaction.fid)
+                    .bind::<Bytea, _>(&reaction.target_hash)
+                    .bind::<Int2, _>(reaction.reaction_type)
+                    .bind::<Timestamp, _>(reaction.timestamp)
+                    .execute(db)?;
+                let removed = diesel::delete(reactions.find(key).filter(r_timestamp.le(reaction.timestamp)))
+                    .returning(Reaction::as_returning())
+                    .get_results(db)?;
+                forget_reactions(db, &removed)?;
+                return Ok(!removed.is_empty());
+            }
+
+            let removed_since = diesel::select(exists(
+                reaction_removals.find(key.clone()).filter(removed_at.ge(reaction.timestamp))
+            )).get_result::<bool>(db)?;
+            if removed_since {
+                return Ok(false);
+            }
+            diesel::delete(reaction_removals.find(key.clone())).execute(db)?;
+            if upsert_reactions(db, std::slice::from_ref(&reaction))? > 0 {
+                return Ok(true);
+            }
+            diesel::update(reactions.find(key).filter(r_timestamp.lt(reaction.timestamp)))
+                .set(r_timestamp.eq(reaction.timestamp))
+                .execute(db)?;
+            Ok(false)
+        })?;
+        Ok(changed)
+    }
+
+    async fn get_reaction_counts(&self, hashes: Vec<Vec<u8>>) -> Result<HashMap<Vec<u8>, ReactionCounts>> {
+        let mut db = self.db_pool.get()?;
+        Ok(load_reaction_counts(&mut db, &hashes)?)
+    }
+
+    async fn get_reactors(&self, hash_q: Vec<u8>, kind: ReactionKind, limit: i64, cursor: Option<Cursor>) -> Result<(Vec<Profile>, Option<Cursor>)> {
+        use crate::schema::reactions::dsl::timestamp as r_timestamp;
+        use crate::schema::users::dsl::fid as u_fid;
+
+        let mut db = self.db_pool.get()?;
+        let mut query = users
+            .inner_join(reactions)
+            .filter(target_hash.eq(&hash_q).and(reaction_type.eq(kind.code())))
+            .select((User::as_select(), r_timestamp))
+            .order((r_timestamp.desc(), u_fid.desc()))
+            .limit(limit + 1)
+            .into_boxed();
+
+        if let Some(cursor) = cursor {
+            let (time, last_fid) = (cursor.time(), cursor.fid());
+            query = query.filter(
+                r_timestamp.lt(time).or(r_timestamp.eq(time).and(u_fid.lt(last_fid)))
+            );
+        }
+
+        let mut rows: Vec<(User, SystemTime)> = query.load(&mut db)?;
+        let next = if rows.len() as i64 > limit {
+            rows.truncate(limit as usize);
+            rows.last().map(|(user, time)| Cursor::for_fid(*time, user.fid))
+        } else {
+            None
+        };
+
+        Ok((rows.into_iter().map(|(user, _)| user.into()).collect(), next))
+    }
+
+    async fn add_viewer_context(&self, viewer: u64, cast_views: Vec<&mut CastView>) -> Result<()> {
+        if cast_views.is_empty() {
+            return Ok(());
+        }
+        let unique = cast_views.iter()
+            .map(|cast| (cast.hash.clone(), cast.fid as i64))
+            .collect::<HashMap<_, _>>();
+        let (hashes, fids): (Vec<_>, Vec<_>) = unique.into_iter().unzip();
+
+        let mut db = self.db_pool.get()?;
+        let context = diesel::sql_query(CAST_VIEWER_CONTEXT_QUERY)
+            .bind::<Int8, _>(viewer as i64)
+            .bind::<Array<Bytea>, _>(hashes)
+            .bind::<Array<Int8>, _>(fids)
+            .load::<CastViewerRow>(&mut db)?
+            .into_iter()
+            .map(|row| (row.hash, CastViewerContext {
+                liked: row.liked,
+                recasted: row.recasted,
+                follows_author: row.follows_author,
+            }))
+            .collect::<HashMap<_, _>>();
+
+        for cast in cast_views {
+            cast.set_viewer_context(context.get(&cast.hash).copied().unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    async fn delete_cast(&self, fid_q: u64, hash_q: Vec<u8>) -> Result<()> {
+        use crate::schema::cast_tombstones::dsl::cast_tombstones;
+
+        let mut db = self.db_pool.get()?;
+        db.transaction::<_, eyre::Error, _>(|db| {
+            let removed = diesel::delete(casts.filter(hash.eq(&hash_q)))
+                .returning(StoredCast::as_returning())
+                .get_results(db)?;
+            if removed.is_empty() {
+                // where it sat in its thread is unknown until the add shows up, if it ever does
+                diesel::insert_into(cast_tombstones)
+                    .values(CastTombstone {
+                        hash: hash_q.clone(),
+                        fid: fid_q as i64,
+                        parent_hash: None,
+                        parent_fid: None,
+                        deleted_at: SystemTime::now(),
+                    })
+                    .on_conflict_do_nothing()
+                    .execute(db)?;
+            }
+            bury_casts(db, &removed)?;
+            Ok(())
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fatline_rs::proto::{CastAddBody, CastRemoveBody, Embed, MessageData, ReactionBody};
+
+    use super::*;
+
+    fn cast_message(body: CastAddBody) -> Message {
+        Message {
+            data: Some(MessageData {
+                r#type: MessageType::CastAdd as i32,
+                fid: 7,
+                timestamp: 1_000,
+                body: Some(MBody::CastAddBody(body)),
+                ..Default::default()
+            }),
+            hash: vec![0xab; 20],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cast_from_reply_with_embeds_and_mentions() {
+        let message = cast_message(CastAddBody {
+            text: "gm  and ".to_string(),
+            mentions: vec![2, 3],
+            mentions_positions: vec![3, 8],
+            embeds: vec![
+                Embed { embed: Some(EmbedKind::Url("https://example.com".to_string())) },
+                Embed { embed: Some(EmbedKind::CastId(CastId { fid: 4, hash: vec![1; 20] })) },
+                Embed { embed: None },
+            ],
+            parent: Some(Parent::ParentCastId(CastId { fid: 5, hash: vec![2; 20] })),
+            ..Default::default()
+        });
+        let cast = cast_from_message(&message).unwrap();
+
+        assert_eq!(cast.hash, vec![0xab; 20]);
+        assert_eq!(cast.fid, 7);
+        assert_eq!(cast.text, "gm  and ");
+        assert_eq!(cast.mentions, vec![2, 3]);
+        assert_eq!(cast.mentions_positions, vec![3, 8]);
+        assert_eq!((cast.parent_fid, cast.parent_hash.clone(), cast.parent_url.clone()), (Some(5), Some(vec![2; 20]), None));
+        assert_eq!(cast.timestamp, fc_time(1_000));
+        assert_eq!(cast.embeds(), vec![
+            CastEmbed::Url("https://example.com".to_string()),
+            CastEmbed::Cast { fid: 4, hash: vec![1; 20] },
+        ]);
+        assert!(cast.is_reply());
+    }
+
+    #[test]
+    fn cast_from_channel_post() {
+        let message = cast_message(CastAddBody {
+            text: "hello".to_string(),
+            parent: Some(Parent::ParentUrl("chain://channel".to_string())),
+            ..Default::default()
+        });
+        let cast = cast_from_message(&message).unwrap();
+        assert_eq!(cast.parent_url.as_deref(), Some("chain://channel"));
+        assert!(!cast.is_reply());
+        assert!(cast.embeds().is_empty());
+    }
+
+    #[test]
+    fn cast_from_other_messages() {
+        let mut removal = cast_message(CastAddBody::default());
+        removal.data.as_mut().unwrap().r#type = MessageType::CastRemove as i32;
+        assert!(cast_from_message(&removal).is_none());
+        assert!(cast_from_message(&Message::default()).is_none());
+    }
+
+    #[test]
+    fn cast_event_from_add_carries_the_cast() {
+        let message = cast_message(CastAddBody { text: "gm".to_string(), ..Default::default() });
+        let event = cast_event_from_message(&message).unwrap();
+        assert_eq!((event.fid, event.hash.clone(), event.removed), (7, vec![0xab; 20], false));
+        assert_eq!(event.cast, cast_from_message(&message));
+    }
+
+    #[test]
+    fn cast_event_from_remove_targets_the_removed_cast() {
+        let mut message = cast_message(CastAddBody::default());
+        let data = message.data.as_mut().unwrap();
+        data.r#type = MessageType::CastRemove as i32;
+        data.body = Some(MBody::CastRemoveBody(CastRemoveBody { target_hash: vec![3; 20] }));
+        let event = cast_event_from_message(&message).unwrap();
+        assert_eq!(event, CastEvent { fid: 7, hash: vec![3; 20], removed: true, cast: None });
+        assert_eq!(cast_event_from_message(&Message::default()), None);
+    }
+
+    fn reaction_message(message_type: MessageType, reaction_type: ReactionType, target: Option<ReactionTarget>) -> Message {
+        Message {
+            data: Some(MessageData {
+                r#type: message_type as i32,
+                fid: 7,
+                timestamp: 1_000,
+                body: Some(MBody::ReactionBody(ReactionBody {
+                    r#type: reaction_type as i32,
+                    target,
+                })),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn cast_target() -> Option<ReactionTarget> {
+        Some(ReactionTarget::TargetCastId(CastId { fid: 5, hash: vec![2; 20] }))
+    }
+
+    #[test]
+    fn reaction_event_from_add_and_remove() {
+        let liked = reaction_event_from_message(&reaction_message(MessageType::ReactionAdd, ReactionType::Like, cast_target())).unwrap();
+        assert_eq!(liked, ReactionEvent {
+            fid: 7,
+            target_fid: 5,
+            target_hash: vec![2; 20],
+            reaction_type: ReactionKind::Like.code(),
+            timestamp: 1_000,
+            removed: false,
+        });
+
+        let unrecast = reaction_event_from_message(&reaction_message(MessageType::ReactionRemove, ReactionType::Recast, cast_target())).unwrap();
+        assert_eq!(unrecast.reaction_type, ReactionKind::Recast.code());
+        assert!(unrecast.removed);
+        // removals aren't reactions to store
+        assert!(reaction_from_message(&reaction_message(MessageType::ReactionRemove, ReactionType::Recast, cast_target())).is_none());
+    }
+
+    #[test]
+    fn reaction_event_ignores_urls_and_unknown_types() {
+        let url = Some(ReactionTarget::TargetUrl("https://example.com".to_string()));
+        assert_eq!(reaction_event_from_message(&reaction_message(MessageType::ReactionAdd, ReactionType::Like, url)), None);
+        assert_eq!(reaction_event_from_message(&reaction_message(MessageType::ReactionAdd, ReactionType::None, cast_target())), None);
+        assert_eq!(reaction_event_from_message(&reaction_message(MessageType::CastAdd, ReactionType::Like, cast_target())), None);
+    }
+}
@@ -0,0 +1,126 @@
+use axum::async_trait;
+use diesel::prelude::*;
+use eyre::Result;
+
+use crate::cast_models::{CastView, Channel, StoredCast};
+use crate::cast_repo::load_reaction_counts;
+use crate::pagination::Cursor;
+use crate::schema::casts::dsl::{casts, hash, parent_url, timestamp};
+use crate::schema::channel_follows::dsl::{channel_follows, channel_id, fid as f_fid};
+use crate::schema::channels::dsl::{channels, id as ch_id};
+use crate::schema::users::dsl::users;
+use crate::service::ServiceState;
+use crate::timeline::TimelineCast;
+use crate::user_models::User;
+
+#[async_trait]
+pub trait ChannelRepository {
+    /// adds a channel to the registry or replaces its url and metadata
+    async fn upsert_channel(&self, channel: Channel) -> Result<Channel>;
+    async fn get_channel(&self, id_q: &str) -> Result<Option<Channel>>;
+    /// casts posted to the channel, newest first
+    async fn get_channel_casts(&self, channel: &Channel, limit: i64, cursor: Option<Cursor>) -> Result<(Vec<TimelineCast>, Option<Cursor>)>;
+    /// returns false if fid_q already followed (or didn't follow) the channel
+    async fn set_channel_follow(&self, fid_q: u64, id_q: &str, following: bool) -> Result<bool>;
+    async fn get_followed_channels(&self, fid_q: u64) -> Result<Vec<Channel>>;
+}
+
+#[async_trait]
+impl ChannelRepository for ServiceState {
+    async fn upsert_channel(&self, channel: Channel) -> Result<Channel> {
+        let mut db = self.db_pool.get()?;
+        Ok(diesel::insert_into(channels)
+            .values(&channel)
+            .on_conflict(ch_id)
+            .do_update()
+            .set(&channel)
+            .returning(Channel::as_returning())
+            .get_result(&mut db)?)
+    }
+
+    async fn get_channel(&self, id_q: &str) -> Result<Option<Channel>> {
+        let mut db = self.db_pool.get()?;
+        Ok(channels.select(Channel::as_select())
+            .filter(ch_id.eq(id_q))
+            .get_result(&mut db)
+            .optional()?)
+    }
+
+    async fn get_channel_casts(&self, channel: &Channel, limit: i64, cursor: Option<Cursor>) -> Result<(Vec<TimelineCast>, Option<Cursor>)> {
+        let mut db = self.db_pool.get()?;
+        let mut query = casts.select(StoredCast::as_select())
+            .filter(parent_url.eq(&channel.url))
+            .order((timestamp.desc(), hash.desc()))
+            .limit(limit + 1)
+            .into_boxed();
+
+        if let Some(cursor) = cursor {
+            let time = cursor.time();
+            query = query.filter(timestamp.lt(time).or(timestamp.eq(time).and(hash.lt(cursor.key))));
+        }
+
+        let mut rows: Vec<StoredCast> = query.load(&mut db)?;
+        let next = if rows.len() as i64 > limit {
+            rows.truncate(limit as usize);
+            rows.last().map(|cast| Cursor::new(cast.timestamp, cast.hash.clone()))
+        } else {
+            None
+        };
+
+        let hashes = rows.iter().map(|cast| cast.hash.clone()).collect::<Vec<_>>();
+        let counts = load_reaction_counts(&mut db, &hashes)?;
+
+        Ok((rows.into_iter().map(|cast| TimelineCast {
+            cast: CastView::from(cast).with_reactions(&counts),
+            recasted_by: None,
+        }).collect(), next))
+    }
+
+    async fn set_channel_follow(&self, fid_q: u64, id_q: &str, following: bool) -> Result<bool> {
+        let mut db = self.db_pool.get()?;
+        let changed = if following {
+            db.transaction::<_, diesel::result::Error, _>(|db| {
+                diesel::insert_into(users)
+                    .values(User::empty(fid_q as i64))
+                    .on_conflict_do_nothing()
+                    .execute(db)?;
+                diesel::insert_into(channel_follows)
+                    .values((f_fid.eq(fid_q as i64), channel_id.eq(id_q)))
+                    .on_conflict_do_nothing()
+                    .execute(db)
+            })?
+        } else {
+            diesel::delete(channel_follows.filter(f_fid.eq(fid_q as i64).and(channel_id.eq(id_q))))
+                .execute(&mut db)?
+        };
+        Ok(changed > 0)
+    }
+
+    async fn get_followed_channels(&self, fid_q: u64) -> Result<Vec<Channel>> {
+        use crate::schema::channel_follows::dsl::timestamp as followed_at;
+
+        let mut db = self.db_pool.get()?;
+        Ok(channels.inner_join(channel_follows)
+            .filter(f_fid.eq(fid_q as i64))
+            .order((followed_at.desc(), ch_id.asc()))
+            .select(Channel::as_select())
+            .load(&mut db)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn channel_cursor_round_trips() {
+        // channel pages are keyed on the cast's timestamp and hash
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let cursor = Cursor::new(time, vec![0xab; 20]);
+        let decoded = Cursor::decode(&cursor.encode()).unwrap();
+        assert_eq!(decoded.time(), time);
+        assert_eq!(decoded.key, vec![0xab; 20]);
+    }
+}
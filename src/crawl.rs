@@ -0,0 +1,223 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use dotenvy::var;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+/// How far a task is from the fid whose request or event started the crawl
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Hop {
+    pub seed: u64,
+    /// hops from the seed to the profiles this task indexes
+    pub depth: u32,
+}
+
+impl Hop {
+    pub fn seed(fid: u64) -> Self {
+        Hop {
+            seed: fid,
+            depth: 0
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        Hop {
+            seed: self.seed,
+            depth: self.depth.saturating_add(1)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CrawlScope {
+    /// follow links out into the whole network
+    Network,
+    /// only index fids that have a signer on this server
+    LocalUsers,
+}
+
+/**
+* Limits on graph expansion, read from CRAWL_MAX_DEPTH, CRAWL_SEED_BUDGET, CRAWL_BUDGET_WINDOW_SECS,
+* CRAWL_RATE (tasks per second, 0 for no limit) and CRAWL_SCOPE ("network" or "local").
+* Budgets and the rate are kept per process, several servers sharing a queue crawl at CRAWL_RATE each.
+*/
+#[derive(Debug, Clone)]
+pub struct CrawlPolicy {
+    pub max_depth: u32,
+    /// crawl tasks a single seed may spawn per budget window
+    pub seed_budget: u32,
+    pub budget_window: Duration,
+    pub rate_per_sec: f64,
+    pub scope: CrawlScope,
+}
+
+fn env_parse<T: FromStr>(name: &str) -> Option<T> {
+    var(name).ok().and_then(|value| T::from_str(&value).ok())
+}
+
+impl CrawlPolicy {
+    pub fn from_env() -> Self {
+        let scope = match var("CRAWL_SCOPE").as_deref() {
+            Ok("local") => CrawlScope::LocalUsers,
+            _ => CrawlScope::Network
+        };
+        CrawlPolicy {
+            max_depth: env_parse("CRAWL_MAX_DEPTH").unwrap_or(1),
+            seed_budget: env_parse("CRAWL_SEED_BUDGET").unwrap_or(5_000),
+            budget_window: Duration::from_secs(env_parse("CRAWL_BUDGET_WINDOW_SECS").unwrap_or(60 * 60)),
+            rate_per_sec: env_parse::<f64>("CRAWL_RATE").unwrap_or(50.0).max(0.0),
+            scope
+        }
+    }
+}
+
+struct SeedBudget {
+    used: u32,
+    started: Instant,
+}
+
+struct RateBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/**
+* Decides how many crawl-spawned tasks get queued, enforcing the depth limit, per-seed budgets and the global crawl rate
+*/
+pub struct CrawlLimiter {
+    policy: CrawlPolicy,
+    budgets: DashMap<u64, SeedBudget>,
+    bucket: Mutex<RateBucket>,
+}
+
+impl CrawlLimiter {
+    pub fn new(policy: CrawlPolicy) -> Self {
+        CrawlLimiter {
+            bucket: Mutex::new(RateBucket {
+                // allow a second's worth of burst
+                tokens: policy.rate_per_sec,
+                refilled: Instant::now()
+            }),
+            budgets: DashMap::new(),
+            policy,
+        }
+    }
+
+    pub fn policy(&self) -> &CrawlPolicy {
+        &self.policy
+    }
+
+    /**
+    * returns how long to hold back each of `wanted` tasks at `hop` that may be queued, charging them to the budgets.
+    * Tasks past the seed's budget are dropped, tasks over the rate are spread out over the following seconds.
+    */
+    pub fn admit(&self, hop: Hop, wanted: usize) -> Vec<Duration> {
+        if wanted == 0 || hop.depth > self.policy.max_depth {
+            return vec![];
+        }
+
+        let mut budget = self.budgets.entry(hop.seed).or_insert_with(|| SeedBudget {
+            used: 0,
+            started: Instant::now()
+        });
+        if budget.started.elapsed() > self.policy.budget_window {
+            budget.used = 0;
+            budget.started = Instant::now();
+        }
+        let admitted = wanted.min(self.policy.seed_budget.saturating_sub(budget.used) as usize);
+        budget.used += admitted as u32;
+        if admitted < wanted {
+            debug!("Crawl from {} at depth {} limited to {admitted} of {wanted} tasks", hop.seed, hop.depth);
+        }
+
+        // the seed's own tasks run straight away
+        let rate = self.policy.rate_per_sec;
+        if hop.depth == 0 || rate <= 0.0 {
+            return vec![Duration::ZERO; admitted];
+        }
+
+        // tokens go negative while tasks are held back, each one waits for its token to be refilled
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(rate.max(1.0));
+        bucket.refilled = now;
+
+        (0..admitted).map(|_| {
+            bucket.tokens -= 1.0;
+            if bucket.tokens >= 0.0 {
+                Duration::ZERO
+            } else {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            }
+        }).collect()
+    }
+
+    /// forgets budgets whose window has passed
+    pub fn evict(&self) {
+        let window = self.policy.budget_window;
+        self.budgets.retain(|_, budget| budget.started.elapsed() <= window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(seed_budget: u32, rate_per_sec: f64) -> CrawlLimiter {
+        CrawlLimiter::new(CrawlPolicy {
+            max_depth: 2,
+            seed_budget,
+            budget_window: Duration::from_secs(60 * 60),
+            rate_per_sec,
+            scope: CrawlScope::Network,
+        })
+    }
+
+    fn at_depth(depth: u32) -> Hop {
+        Hop { seed: 1, depth }
+    }
+
+    #[test]
+    fn admits_nothing_past_max_depth() {
+        assert!(limiter(100, 0.0).admit(at_depth(3), 10).is_empty());
+        assert_eq!(limiter(100, 0.0).admit(at_depth(2), 10).len(), 10);
+    }
+
+    #[test]
+    fn seed_budget_is_shared_across_calls() {
+        let limiter = limiter(5, 0.0);
+        assert_eq!(limiter.admit(at_depth(1), 3).len(), 3);
+        assert_eq!(limiter.admit(at_depth(1), 3).len(), 2);
+        assert!(limiter.admit(at_depth(1), 3).is_empty());
+        // other seeds have their own
+        assert_eq!(limiter.admit(Hop { seed: 2, depth: 1 }, 3).len(), 3);
+    }
+
+    #[test]
+    fn over_rate_tasks_are_delayed() {
+        let delays = limiter(100, 2.0).admit(at_depth(1), 5);
+        assert_eq!(delays.len(), 5);
+        assert_eq!(&delays[..2], &[Duration::ZERO, Duration::ZERO]);
+        for (delay, expected) in delays[2..].iter().zip([0.5, 1.0, 1.5]) {
+            assert!((delay.as_secs_f64() - expected).abs() < 0.05, "expected about {expected}s, got {delay:?}");
+        }
+    }
+
+    #[test]
+    fn delays_carry_over_between_calls() {
+        let limiter = limiter(100, 1.0);
+        assert_eq!(limiter.admit(at_depth(1), 1), vec![Duration::ZERO]);
+        let delay = limiter.admit(at_depth(1), 1)[0];
+        assert!(delay > Duration::from_millis(900) && delay <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn seed_tasks_skip_the_rate() {
+        let limiter = limiter(3, 1.0);
+        assert_eq!(limiter.admit(at_depth(0), 5), vec![Duration::ZERO; 3]);
+    }
+}
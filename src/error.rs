@@ -3,5 +3,7 @@ use thiserror::Error;
 #[derive(Debug, Error)]
 pub enum ServerError {
     #[error("There was a problem with the database")]
-    DbError
+    DbError,
+    #[error("The task queue is full")]
+    QueueFull
 }
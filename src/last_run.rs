@@ -0,0 +1,130 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+use eyre::Result;
+use tracing::{debug, error};
+
+use crate::schedule::SharedSchedule;
+use crate::schema::task_last_run::dsl::{last_run, task_key, task_last_run};
+use crate::service::DbPool;
+
+// the in-memory map never holds more than this, the oldest entries are dropped and reloaded from the db if needed
+const MAX_ENTRIES: usize = 50_000;
+// share of MAX_ENTRIES dropped at once, so a full map isn't cut back on every insert
+const DROP_PERCENT: usize = 10;
+
+fn to_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/**
+* When each task key last ran, cached in memory and persisted so throttles survive restarts.
+* Entries older than the schedule's longest interval can't throttle anything anymore,
+* `evict` should be run periodically to sweep them out.
+*/
+pub struct LastRunMap {
+    entries: DashMap<String, u64>,
+    db_pool: DbPool,
+    schedule: SharedSchedule,
+}
+
+impl LastRunMap {
+    pub fn new(db_pool: DbPool, schedule: SharedSchedule) -> Self {
+        LastRunMap {
+            entries: DashMap::new(),
+            db_pool,
+            schedule,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<u64> {
+        if let Some(entry) = self.entries.get(key) {
+            return Some(*entry.value());
+        }
+        match self.load(key) {
+            Ok(Some(time)) => {
+                let secs = to_secs(time);
+                self.entries.insert(key.to_string(), secs);
+                Some(secs)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                error!("Couldn't load last run for {key} {e}");
+                None
+            }
+        }
+    }
+
+    pub fn record(&self, key: String, secs: u64) {
+        if let Err(e) = self.store(&key, secs) {
+            error!("Couldn't persist last run for {key} {e}");
+        }
+        self.entries.insert(key, secs);
+        if self.entries.len() > MAX_ENTRIES {
+            self.drop_oldest();
+        }
+    }
+
+    // they're still persisted, so throttling them only costs a db read
+    fn drop_oldest(&self) {
+        let mut times = self.entries.iter().map(|entry| *entry.value()).collect::<Vec<_>>();
+        let keep = MAX_ENTRIES * (100 - DROP_PERCENT) / 100;
+        if times.len() <= keep {
+            return;
+        }
+        let drop = times.len() - keep;
+        let (_, cutoff, _) = times.select_nth_unstable(drop - 1);
+        let cutoff = *cutoff;
+        self.entries.retain(|_, secs| *secs > cutoff);
+        debug!("Dropped {} oldest last run entries", times.len() - self.entries.len());
+    }
+
+    /// the most recent in-memory last runs, newest first
+    pub fn recent(&self, limit: usize) -> Vec<(String, u64)> {
+        let mut runs: Vec<(String, u64)> = self.entries.iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        runs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        runs.truncate(limit);
+        runs
+    }
+
+    /// drops anything too old to matter, from memory and the db
+    pub fn evict(&self) {
+        // read each time, intervals can be changed at runtime
+        let ttl = self.schedule.read().unwrap().longest_interval();
+        let cutoff = SystemTime::now() - ttl;
+        let cutoff_secs = to_secs(cutoff);
+        let before = self.entries.len();
+        self.entries.retain(|_, secs| *secs >= cutoff_secs);
+        debug!("Evicted {} last run entries", before - self.entries.len());
+
+        let deleted = self.db_pool.get().map_err(eyre::Error::from).and_then(|mut db| {
+            Ok(diesel::delete(task_last_run.filter(last_run.lt(cutoff))).execute(&mut db)?)
+        });
+        if let Err(e) = deleted {
+            error!("Couldn't evict persisted last runs {e}");
+        }
+    }
+
+    fn load(&self, key: &str) -> Result<Option<SystemTime>> {
+        let mut db = self.db_pool.get()?;
+        Ok(task_last_run.select(last_run)
+            .filter(task_key.eq(key))
+            .get_result(&mut db)
+            .optional()?)
+    }
+
+    fn store(&self, key: &str, secs: u64) -> Result<()> {
+        let mut db = self.db_pool.get()?;
+        diesel::insert_into(task_last_run)
+            .values((task_key.eq(key), last_run.eq(UNIX_EPOCH + Duration::from_secs(secs))))
+            .on_conflict(task_key)
+            .do_update()
+            .set(last_run.eq(excluded(last_run)))
+            .execute(&mut db)?;
+        Ok(())
+    }
+}
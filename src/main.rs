@@ -1,15 +1,15 @@
+use std::collections::BTreeMap;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 use axum::{Error, Extension, Json, middleware::from_fn_with_state, Router, routing::get};
 use axum::body::{Body, Bytes};
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
 use axum::http::{HeaderMap, StatusCode};
 use axum::response::IntoResponse;
-use axum::routing::post;
+use axum::routing::{delete, post, put};
+use chrono::{DateTime, Utc};
 use clap::{Parser, Subcommand};
-use crossbeam::channel;
-use crossbeam::channel::{bounded, Sender};
 use dashmap::DashMap;
 use dotenvy::var;
 use eyre::{bail, Result};
@@ -23,16 +23,34 @@ use serde::{Deserialize, Serialize};
 use tracing::{debug, error};
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
-use crate::{auth_layer::fid_sig_auth_middleware, service::ServiceState};
+use crate::{auth_layer::{admin_middleware, AdminFids, fid_sig_auth_middleware}, service::ServiceState};
+use crate::cache_layer::{cache_middleware, CachePolicy, LastModified};
+use crate::error::ServerError;
+use crate::last_run::LastRunMap;
+use crate::queue::{KindCount, Replay, TaskQueue};
+use crate::refresher::Refresher;
+use crate::schedule::{KindSchedule, SchedulePolicy, SharedSchedule};
+use crate::stats::{InFlightTask, TaskFailure, Throughput, WorkerStats};
 use crate::signer_repo::SignerRepository;
+use crate::suggestion_repo::{Suggestion, SuggestionRepository};
 use crate::subscriber::{signer_from_event, Subscriber};
-use crate::user_models::{Link, Signer};
-use crate::user_repo::{FollowDirection, UserRepository};
-use crate::worker::{Task, Worker};
+use crate::user_models::{Link, Signer, ViewerProfile};
+use crate::pagination::{Cursor, page_limit, PageQuery, ProfilePage};
+use crate::timeline::{TimelineFilter, TimelinePage};
+use crate::timeline_repo::TimelineRepository;
+use crate::thread::{ThreadPage, ThreadReply};
+use crate::thread_repo::ThreadRepository;
+use crate::cast_repo::CastRepository;
+use crate::cast_models::{CastView, Channel, ReactionKind};
+use crate::channel_repo::ChannelRepository;
+use crate::render::{render_casts, RenderQuery};
+use crate::user_repo::{FOLLOW_LINK_TYPE, FollowDirection, UserRepository, ViewerOverlap};
+use crate::worker::{Origin, Priority, Task, TaskEnvelope, TaskKind, Worker, WorkerConfig};
 
 mod schema;
 mod service;
 mod auth_layer;
+mod cache_layer;
 mod timeline;
 mod user_models;
 mod user_repo;
@@ -41,6 +59,22 @@ mod signer_repo;
 mod error;
 mod subscriber;
 mod notifier;
+mod queue;
+mod last_run;
+mod pagination;
+mod views;
+mod suggestion_repo;
+mod refresher;
+mod crawl;
+mod schedule;
+mod stats;
+mod cast_models;
+mod cast_repo;
+mod timeline_repo;
+mod thread;
+mod thread_repo;
+mod channel_repo;
+mod render;
 
 // constants for headers
 // required headers: pub_hex, timestamp, sig, fid
@@ -52,6 +86,12 @@ const SIGNATURE_DATA_HEADER: &'static str = "extra_sig_data_hex";
 const SIGNATURE_HEADER: &'static str = "sig";
 const FID_HEADER: &'static str = "fid";
 
+// every read route carries viewer context so responses are private to the authenticated fid
+const PROFILE_CACHE: CachePolicy = CachePolicy::new(60, true);
+const FOLLOW_LIST_CACHE: CachePolicy = CachePolicy::new(120, true);
+const TIMELINE_CACHE: CachePolicy = CachePolicy::new(15, true);
+const THREAD_CACHE: CachePolicy = CachePolicy::new(30, true);
+
 type ServiceArcState = State<Arc<ServiceState>>;
 
 #[derive(Parser, Debug)]
@@ -72,12 +112,30 @@ enum Commands {
     },
     /// Run the server normally
     #[command(name= "run")]
-    Run{}
+    Run{},
+    /// Inspect and replay index tasks that ran out of retries
+    #[command(name = "dead-letters", subcommand)]
+    DeadLetters(DeadLetterCommands)
+}
+
+#[derive(Debug, Subcommand)]
+enum DeadLetterCommands {
+    /// List the most recently failed tasks
+    #[command(name = "list")]
+    List {
+        #[arg(long, default_value_t = 50)]
+        limit: i64
+    },
+    /// Put a dead task back on the queue
+    #[command(name = "replay", arg_required_else_help = true)]
+    Replay {
+        #[arg(required = true)]
+        task_id: i64
+    }
 }
 
 async fn index_signers(fid: u64) -> Result<()> {
-    let (s,r) = bounded(1);
-    let mut state = ServiceState::new(s).await;
+    let mut state = ServiceState::new().await;
 
     let mut hub_client = ServiceState::hub_client().await;
 
@@ -97,6 +155,29 @@ async fn index_signers(fid: u64) -> Result<()> {
     Ok(())
 }
 
+async fn dead_letters(command: DeadLetterCommands) -> Result<()> {
+    let queue = TaskQueue::new(ServiceState::db_pool(1).await, 0);
+
+    match command {
+        DeadLetterCommands::List { limit } => {
+            for dead in queue.dead_tasks(limit)? {
+                let failed_at: DateTime<Utc> = dead.failed_at.into();
+                println!("{}\t{}\t{} attempts\t{}\t{}",
+                         dead.task_id, failed_at.to_rfc3339(), dead.attempts, dead.task_key, dead.last_error);
+            }
+        }
+        DeadLetterCommands::Replay { task_id } => {
+            match queue.replay(task_id)? {
+                Replay::Queued => println!("Replayed task {task_id}"),
+                Replay::AlreadyPending => bail!("An identical task is already queued, kept dead task {task_id}"),
+                Replay::NotFound => bail!("No dead task {task_id} to replay"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
 
@@ -113,20 +194,29 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    if let Commands::SyncSigner{ fid } = args.command {
-        index_signers(fid).await?;
-        return Ok(())
+    match args.command {
+        Commands::SyncSigner { fid } => {
+            index_signers(fid).await?;
+            return Ok(())
+        }
+        Commands::DeadLetters(command) => {
+            return dead_letters(command).await
+        }
+        Commands::Run {} => {}
     };
 
     let bind_addr = var("BIND_ADDR").unwrap_or("127.0.0.1:8000".to_string());
+    let suggestions_refresh_secs = var("SUGGESTIONS_REFRESH_SECS").ok()
+        .and_then(|secs| u64::from_str(&secs).ok())
+        .unwrap_or(15 * 60);
 
     debug!("Initializing resources");
 
-    let index_map = Arc::new(DashMap::new());
-    let (sender, receiver) = channel::unbounded();
-
-    let service = ServiceState::new(sender.clone()).await;
-    let worker_service = ServiceState::new(sender.clone()).await;
+    let service = ServiceState::new().await;
+    let worker_service = ServiceState::new().await;
+    let schedule = SchedulePolicy::from_env().shared();
+    let index_map = Arc::new(LastRunMap::new(worker_service.db_pool.clone(), schedule.clone()));
+    let worker_stats = Arc::new(WorkerStats::new());
 
     let service_arc = Arc::new(service);
     debug!("Initialized server resources [1/2]");
@@ -134,16 +224,56 @@ async fn main() -> Result<()> {
     let worker_service = Arc::new(worker_service);
     debug!("Initialized worker resources [2/2]");
 
-    let worker = Worker::new(worker_service, receiver.clone(), index_map.clone());
-    let subscriber = Subscriber::new(sender.clone()).await;
+    let refresher = Refresher::new(worker_service.clone(), Duration::from_secs(suggestions_refresh_secs));
+    let subscriber = Subscriber::new(worker_service.task_queue.clone()).await;
+    let worker = Worker::new(worker_service, index_map.clone(), schedule.clone(), worker_stats.clone(), WorkerConfig::from_env());
+
+    let admin = Router::new()
+        .route("/admin/schedule", get(get_schedule))
+        .route("/admin/schedule/:kind", put(set_kind_schedule))
+        .route("/admin/queue", get(get_queue_report))
+        .route("/admin/tasks", post(enqueue_task))
+        .route("/admin/tasks/:task_id", delete(cancel_task))
+        .route("/admin/channels/:id", put(set_channel))
+        .layer(Extension(schedule))
+        .layer(Extension(worker_stats))
+        .layer(Extension(index_map))
+        .route_layer(from_fn_with_state(AdminFids::from_env(), admin_middleware));
 
     let app = Router::new()
-        .route("/profile/me", get(current_user_profile))
-        .route("/profile/:fid", get(get_user_profile))
-        .route("/profile/:fid/follows", get(get_user_followed_by))
-        .route("/profile/:fid/following", get(get_user_following))
+        .route("/profile/me", get(current_user_profile)
+            .layer(from_fn_with_state(CachePolicy::new(0, true), cache_middleware)))
+        .route("/profile/:fid", get(get_user_profile)
+            .layer(from_fn_with_state(PROFILE_CACHE, cache_middleware)))
+        .route("/profile/:fid/follows", get(get_user_followed_by)
+            .layer(from_fn_with_state(FOLLOW_LIST_CACHE, cache_middleware)))
+        .route("/profile/:fid/following", get(get_user_following)
+            .layer(from_fn_with_state(FOLLOW_LIST_CACHE, cache_middleware)))
+        .route("/profile/:fid/mutuals", get(get_user_mutuals)
+            .layer(from_fn_with_state(FOLLOW_LIST_CACHE, cache_middleware)))
+        .route("/profile/:fid/known_followers", get(get_user_known_followers)
+            .layer(from_fn_with_state(FOLLOW_LIST_CACHE, cache_middleware)))
+        .route("/suggestions", get(get_suggestions)
+            .layer(from_fn_with_state(CachePolicy::new(300, true), cache_middleware)))
+        .route("/search/users", get(search_users)
+            .layer(from_fn_with_state(CachePolicy::new(30, true), cache_middleware)))
+        .route("/timeline/home", get(get_home_timeline)
+            .layer(from_fn_with_state(TIMELINE_CACHE, cache_middleware)))
+        .route("/cast/:id/:hash/thread", get(get_cast_thread)
+            .layer(from_fn_with_state(THREAD_CACHE, cache_middleware)))
+        // axum wants one name per parameter position, here it's a fid or a cast hash
+        .route("/cast/:id/likes", get(get_cast_likes)
+            .layer(from_fn_with_state(FOLLOW_LIST_CACHE, cache_middleware)))
+        .route("/cast/:id/recasters", get(get_cast_recasters)
+            .layer(from_fn_with_state(FOLLOW_LIST_CACHE, cache_middleware)))
+        .route("/channel/:id/casts", get(get_channel_casts)
+            .layer(from_fn_with_state(TIMELINE_CACHE, cache_middleware)))
+        .route("/channel/:id/follow", put(follow_channel).delete(unfollow_channel))
+        .route("/channels/following", get(get_followed_channels)
+            .layer(from_fn_with_state(CachePolicy::new(0, true), cache_middleware)))
         .route("/submit_message", post(submit_message))
         .route("/submit_messages", post(submit_messages))
+        .merge(admin)
         .route_layer(from_fn_with_state(service_arc.clone(), fid_sig_auth_middleware))
         .with_state(service_arc);
 
@@ -151,28 +281,49 @@ async fn main() -> Result<()> {
     let tcp_listener = TcpListener::bind(bind_addr).await.expect("Couldn't create tcp listener");
     axum::serve(tcp_listener, app).await?;
 
-    worker.cancel();
     subscriber.cancel();
+    refresher.cancel();
+    worker.shutdown().await;
 
     Ok(())
 }
 
-fn queue_index_fid(sender: &Sender<Task>, fid: u64) {
-    if let Err(e) = sender.send(Task::IndexFid(fid, false)) {
-        error!("Couldn't queue fid index task {e}");
+// a full queue is answered with 503 so clients back off rather than their refreshes being dropped
+fn enqueue_status(e: eyre::Report) -> StatusCode {
+    match e.downcast_ref::<ServerError>() {
+        Some(ServerError::QueueFull) => StatusCode::SERVICE_UNAVAILABLE,
+        _ => {
+            error!("Couldn't queue task {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
     }
 }
 
-fn queue_index_links(sender: &Sender<Task>, fid: u64) {
-    if let Err(e) = sender.send(Task::IndexLinks(fid)) {
-        error!("Couldn't queue fid link index task {e}");
+fn queue_user_task(queue: &TaskQueue, task: Task) -> Result<(), StatusCode> {
+    match queue.enqueue(TaskEnvelope::new(task, Origin::User)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(enqueue_status(e))
     }
 }
 
-fn queue_index_casts(sender: &Sender<Task>, fid: u64) {
-    if let Err(e) = sender.send(Task::IndexFidCasts(fid, false)) {
-        error!("Couldn't queue fid cast index task {e}");
-    }
+fn queue_index_fid(queue: &TaskQueue, fid: u64) -> Result<(), StatusCode> {
+    queue_user_task(queue, Task::IndexFid(fid, false))
+}
+
+fn queue_index_links(queue: &TaskQueue, fid: u64) -> Result<(), StatusCode> {
+    queue_user_task(queue, Task::IndexLinks(fid, false))
+}
+
+fn queue_index_casts(queue: &TaskQueue, fid: u64) -> Result<(), StatusCode> {
+    queue_user_task(queue, Task::IndexFidCasts(fid, false))
+}
+
+fn queue_index_cast(queue: &TaskQueue, fid: u64, hash: Vec<u8>) -> Result<(), StatusCode> {
+    queue_user_task(queue, Task::IndexCast(fid, hash))
+}
+
+fn queue_index_channel(queue: &TaskQueue, url: String) -> Result<(), StatusCode> {
+    queue_user_task(queue, Task::IndexChannel(url))
 }
 
 async fn handle_message(message: Vec<u8>, signer: &Signer, hub_service: &mut HubService) -> Result<()> {
@@ -192,6 +343,76 @@ async fn handle_message(message: Vec<u8>, signer: &Signer, hub_service: &mut Hub
     Ok(())
 }
 
+#[derive(Deserialize, Debug)]
+pub struct LinkTypeQuery {
+    pub link_type: Option<String>,
+}
+
+impl LinkTypeQuery {
+    fn link_type(&self) -> &str {
+        self.link_type.as_deref().unwrap_or(FOLLOW_LINK_TYPE)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserSearchQuery {
+    pub q: String,
+    pub page_size: Option<u32>,
+    pub boost_following: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ManualTask {
+    pub task: Task,
+    /// defaults to the interactive lane
+    pub priority: Option<Priority>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Enqueued {
+    /// false if it was coalesced into an identical pending task
+    pub queued: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueueReport {
+    pub pending: Vec<KindCount>,
+    pub in_flight: Vec<InFlightTask>,
+    pub recent_failures: Vec<TaskFailure>,
+    /// throttle key and unix seconds of its last successful run
+    pub last_runs: Vec<(String, u64)>,
+    pub throughput: Throughput,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TimelineQuery {
+    pub page_size: Option<u32>,
+    pub page_token: Option<String>,
+    pub include_replies: Option<bool>,
+    pub include_recasts: Option<bool>,
+    pub include_channels: Option<bool>,
+}
+
+impl TimelineQuery {
+    fn filter(&self) -> TimelineFilter {
+        TimelineFilter {
+            replies: self.include_replies.unwrap_or(false),
+            recasts: self.include_recasts.unwrap_or(false),
+            // following a channel already asks for its casts
+            channels: self.include_channels.unwrap_or(true),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChannelUpdate {
+    /// the parent_url casts in the channel are posted under
+    pub url: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Messages {
     pub updates: Vec<Vec<u8>>
@@ -227,47 +448,61 @@ async fn current_user_profile(
     Extension(profile): Extension<Profile>
 ) -> Result<Json<Profile>, StatusCode> {
 
-    queue_index_fid(&state.work_sender, profile.fid);
-    queue_index_links(&state.work_sender, profile.fid);
-    queue_index_casts(&state.work_sender, profile.fid);
+    queue_index_fid(&state.task_queue, profile.fid)?;
+    queue_index_links(&state.task_queue, profile.fid)?;
+    queue_index_casts(&state.task_queue, profile.fid)?;
 
     Ok(Json::from(profile))
 }
 
 async fn get_user_profile(
     State(state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
     Path(fid): Path<u64>
-) -> Result<Json<Profile>, StatusCode> {
+) -> Result<Json<ViewerProfile>, StatusCode> {
+
+    queue_index_fid(&state.task_queue, fid)?;
+    queue_index_links(&state.task_queue, fid)?;
+    queue_index_casts(&state.task_queue, fid)?;
 
-    queue_index_fid(&state.work_sender, fid);
-    queue_index_links(&state.work_sender, fid);
-    queue_index_casts(&state.work_sender, fid);
+    let profile = state.get_user_profile(fid, false).await.map_err(|_| StatusCode::NOT_FOUND)?;
 
-    match state.get_user_profile(fid, false).await {
-        Ok(profile) => {
-            Ok(Json(profile))
+    match state.with_viewer_context(viewer.fid, vec![profile]).await {
+        Ok(mut profiles) => {
+            Ok(Json(profiles.remove(0)))
         }
-        Err(_) => {
-            Err(StatusCode::NOT_FOUND)
+        Err(e) => {
+            error!("Couldn't get viewer context {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
 }
 
 async fn get_user_following(
     State(mut state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
     Path(fid): Path<u64>,
-) -> Result<Json<Vec<Profile>>, StatusCode> {
+    Query(filter): Query<LinkTypeQuery>,
+) -> Result<Json<Vec<ViewerProfile>>, StatusCode> {
 
-    queue_index_fid(&state.work_sender, fid);
-    queue_index_links(&state.work_sender, fid);
-    queue_index_casts(&state.work_sender, fid);
+    queue_index_fid(&state.task_queue, fid)?;
+    queue_index_links(&state.task_queue, fid)?;
+    queue_index_casts(&state.task_queue, fid)?;
 
-    match state.get_profile_links(fid, true, FollowDirection::Following).await {
-        Ok(links) => {
-            Ok(Json(links))
-        },
+    let links = match state.get_profile_links(fid, true, FollowDirection::Following, Some(filter.link_type())).await {
+        Ok(links) => links,
         Err(e) => {
             error!("Couldn't get profile links {e}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    };
+
+    match state.with_viewer_context(viewer.fid, links).await {
+        Ok(profiles) => {
+            Ok(Json(profiles))
+        },
+        Err(e) => {
+            error!("Couldn't get viewer context {e}");
             Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
@@ -275,19 +510,438 @@ async fn get_user_following(
 
 async fn get_user_followed_by(
     State(mut state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
     Path(fid): Path<u64>,
-) -> Result<Json<Vec<Profile>>, StatusCode> {
+    Query(filter): Query<LinkTypeQuery>,
+) -> Result<Json<Vec<ViewerProfile>>, StatusCode> {
 
-    queue_index_fid(&state.work_sender, fid);
-    queue_index_links(&state.work_sender, fid);
-    queue_index_casts(&state.work_sender, fid);
+    queue_index_fid(&state.task_queue, fid)?;
+    queue_index_links(&state.task_queue, fid)?;
+    queue_index_casts(&state.task_queue, fid)?;
 
-    match state.get_profile_links(fid, true, FollowDirection::FollowedBy).await {
-        Ok(links) => {
-            Ok(Json(links))
-        },
+    let links = match state.get_profile_links(fid, true, FollowDirection::FollowedBy, Some(filter.link_type())).await {
+        Ok(links) => links,
         Err(e) => {
             error!("Couldn't get profile links {e}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    };
+
+    match state.with_viewer_context(viewer.fid, links).await {
+        Ok(profiles) => {
+            Ok(Json(profiles))
+        },
+        Err(e) => {
+            error!("Couldn't get viewer context {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn get_schedule(
+    Extension(schedule): Extension<SharedSchedule>
+) -> Json<BTreeMap<&'static str, KindSchedule>> {
+    Json(schedule.read().unwrap().entries())
+}
+
+async fn set_kind_schedule(
+    Extension(schedule): Extension<SharedSchedule>,
+    Path(kind): Path<String>,
+    Json(kind_schedule): Json<KindSchedule>,
+) -> Result<Json<BTreeMap<&'static str, KindSchedule>>, StatusCode> {
+    let kind = TaskKind::from_name(&kind).ok_or(StatusCode::NOT_FOUND)?;
+    let mut policy = schedule.write().unwrap();
+    policy.set(kind, kind_schedule);
+    debug!("Updated {} schedule to {kind_schedule:?}", kind.name());
+    Ok(Json(policy.entries()))
+}
+
+async fn set_channel(
+    State(state): ServiceArcState,
+    Path(id): Path<String>,
+    Json(update): Json<ChannelUpdate>,
+) -> Result<Json<Channel>, StatusCode> {
+    let channel = Channel {
+        id,
+        url: update.url,
+        name: update.name.unwrap_or_default(),
+        description: update.description.unwrap_or_default(),
+        image_url: update.image_url,
+    };
+    match state.upsert_channel(channel).await {
+        Ok(channel) => {
+            debug!("Registered channel {} at {}", channel.id, channel.url);
+            queue_index_channel(&state.task_queue, channel.url.clone())?;
+            Ok(Json(channel))
+        }
+        Err(e) => {
+            error!("Couldn't register channel {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+// how many last runs the queue report includes
+const REPORT_LAST_RUNS: usize = 100;
+
+async fn get_queue_report(
+    State(state): ServiceArcState,
+    Extension(stats): Extension<Arc<WorkerStats>>,
+    Extension(index_map): Extension<Arc<LastRunMap>>,
+) -> Result<Json<QueueReport>, StatusCode> {
+    let pending = state.task_queue.pending_counts().map_err(|e| {
+        error!("Couldn't count pending tasks {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(QueueReport {
+        pending,
+        in_flight: stats.in_flight(),
+        recent_failures: stats.recent_failures(),
+        last_runs: index_map.recent(REPORT_LAST_RUNS),
+        throughput: stats.throughput(),
+    }))
+}
+
+async fn enqueue_task(
+    State(state): ServiceArcState,
+    Json(manual): Json<ManualTask>,
+) -> Result<Json<Enqueued>, StatusCode> {
+    let mut envelope = TaskEnvelope::new(manual.task, Origin::Admin);
+    if let Some(priority) = manual.priority {
+        envelope = envelope.with_priority(priority);
+    }
+    match state.task_queue.enqueue(envelope) {
+        Ok(queued) => Ok(Json(Enqueued { queued })),
+        Err(e) => Err(enqueue_status(e))
+    }
+}
+
+async fn cancel_task(
+    State(state): ServiceArcState,
+    Path(task_id): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    match state.task_queue.cancel(task_id) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        // running tasks can't be cancelled, only ones still waiting
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Couldn't cancel task {task_id} {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn get_user_mutuals(
+    State(state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
+    Path(fid): Path<u64>,
+    Query(page): Query<PageQuery>,
+) -> Result<Json<ProfilePage>, StatusCode> {
+    get_viewer_overlap_page(&state, viewer.fid, fid, ViewerOverlap::Mutuals, page).await
+}
+
+async fn get_user_known_followers(
+    State(state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
+    Path(fid): Path<u64>,
+    Query(page): Query<PageQuery>,
+) -> Result<Json<ProfilePage>, StatusCode> {
+    get_viewer_overlap_page(&state, viewer.fid, fid, ViewerOverlap::KnownFollowers, page).await
+}
+
+async fn get_viewer_overlap_page(
+    state: &ServiceState,
+    viewer: u64,
+    fid: u64,
+    overlap: ViewerOverlap,
+    page: PageQuery,
+) -> Result<Json<ProfilePage>, StatusCode> {
+    let cursor = page.cursor().map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let (profiles, next) = state.get_viewer_overlap(viewer, fid, overlap, page.limit(), cursor).await
+        .map_err(|e| {
+            error!("Couldn't get {overlap:?} for {fid} {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let profiles = state.with_viewer_context(viewer, profiles).await
+        .map_err(|e| {
+            error!("Couldn't get viewer context {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(ProfilePage {
+        profiles,
+        next_page: next.map(|c| c.encode()),
+    }))
+}
+
+async fn get_home_timeline(
+    State(state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
+    Query(query): Query<TimelineQuery>,
+    Query(render): Query<RenderQuery>,
+) -> Result<(Option<LastModified>, Json<TimelinePage>), StatusCode> {
+    let cursor = query.page_token.as_deref().map(Cursor::decode).transpose()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let (casts, next) = state.get_home_timeline(viewer.fid, query.filter(), page_limit(query.page_size), cursor).await
+        .map_err(|e| {
+            error!("Couldn't get home timeline for {} {e}", viewer.fid);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut page = TimelinePage {
+        casts,
+        next_page: next.map(|c| c.encode()),
+    };
+    state.add_viewer_context(viewer.fid, page.casts_mut()).await.map_err(|e| {
+        error!("Couldn't get cast viewer context {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    render_casts(&state, page.casts_mut(), render.render).await.map_err(|e| {
+        error!("Couldn't render casts {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let last_modified = LastModified::newest(page.casts.iter().map(|timeline_cast| timeline_cast.cast.timestamp));
+    Ok((last_modified, Json(page)))
+}
+
+async fn get_cast_thread(
+    State(state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
+    Path((fid, hash)): Path<(u64, String)>,
+    Query(page): Query<PageQuery>,
+    Query(render): Query<RenderQuery>,
+) -> Result<(Option<LastModified>, Json<ThreadPage>), StatusCode> {
+    let hash = hex::decode(hash.trim_start_matches("0x")).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let cursor = page.cursor().map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    // fills in missing ancestors and replies for the next load
+    queue_index_cast(&state.task_queue, fid, hash.clone())?;
+
+    let stored = state.get_cast(hash.clone()).await.map_err(|e| {
+        error!("Couldn't get cast {} {e}", hex::encode(&hash));
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let cast = match stored {
+        Some(cast) => cast,
+        None => state.fetch_and_store_cast(fid, hash.clone()).await.map_err(|e| {
+            debug!("Couldn't fetch cast {} by {fid} {e}", hex::encode(&hash));
+            StatusCode::NOT_FOUND
+        })?
+    };
+    if cast.fid as u64 != fid {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let thread = async {
+        let ancestors = state.get_thread_ancestors(&cast).await?;
+        let reply_count = state.count_replies(hash.clone()).await?;
+        let (replies, next) = state.get_thread_replies(viewer.fid, hash.clone(), page.limit(), cursor).await?;
+        let counts = state.get_reaction_counts(vec![hash.clone()]).await?;
+        Ok::<_, eyre::Error>((ancestors, reply_count, replies, next, counts))
+    };
+    let (ancestors, reply_count, replies, next, counts) = thread.await.map_err(|e| {
+        error!("Couldn't get thread for {} {e}", hex::encode(&hash));
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut thread_page = ThreadPage {
+        ancestors,
+        cast: ThreadReply {
+            cast: CastView::from(cast).with_reactions(&counts),
+            reply_count,
+            replies,
+        },
+        next_page: next.map(|c| c.encode()),
+    };
+    state.add_viewer_context(viewer.fid, thread_page.casts_mut()).await.map_err(|e| {
+        error!("Couldn't get cast viewer context {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    render_casts(&state, thread_page.casts_mut(), render.render).await.map_err(|e| {
+        error!("Couldn't render casts {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let last_modified = LastModified::newest(thread_page.casts_mut().into_iter().map(|cast| cast.timestamp));
+    Ok((last_modified, Json(thread_page)))
+}
+
+async fn get_cast_likes(
+    State(state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
+    Path(hash): Path<String>,
+    Query(page): Query<PageQuery>,
+) -> Result<Json<ProfilePage>, StatusCode> {
+    get_reactors_page(&state, viewer.fid, &hash, ReactionKind::Like, page).await
+}
+
+async fn get_cast_recasters(
+    State(state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
+    Path(hash): Path<String>,
+    Query(page): Query<PageQuery>,
+) -> Result<Json<ProfilePage>, StatusCode> {
+    get_reactors_page(&state, viewer.fid, &hash, ReactionKind::Recast, page).await
+}
+
+async fn get_reactors_page(
+    state: &ServiceState,
+    viewer: u64,
+    hash: &str,
+    kind: ReactionKind,
+    page: PageQuery,
+) -> Result<Json<ProfilePage>, StatusCode> {
+    let hash = hex::decode(hash.trim_start_matches("0x")).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let cursor = page.cursor().map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let (profiles, next) = state.get_reactors(hash.clone(), kind, page.limit(), cursor).await
+        .map_err(|e| {
+            error!("Couldn't get {kind:?} reactors for {} {e}", hex::encode(&hash));
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let profiles = state.with_viewer_context(viewer, profiles).await
+        .map_err(|e| {
+            error!("Couldn't get viewer context {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(ProfilePage {
+        profiles,
+        next_page: next.map(|c| c.encode()),
+    }))
+}
+
+async fn get_channel_casts(
+    State(state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
+    Path(id): Path<String>,
+    Query(page): Query<PageQuery>,
+    Query(render): Query<RenderQuery>,
+) -> Result<(Option<LastModified>, Json<TimelinePage>), StatusCode> {
+    let cursor = page.cursor().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let channel = get_known_channel(&state, &id).await?;
+
+    queue_index_channel(&state.task_queue, channel.url.clone())?;
+
+    let (casts, next) = state.get_channel_casts(&channel, page.limit(), cursor).await
+        .map_err(|e| {
+            error!("Couldn't get casts for channel {id} {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut feed = TimelinePage {
+        casts,
+        next_page: next.map(|c| c.encode()),
+    };
+    state.add_viewer_context(viewer.fid, feed.casts_mut()).await.map_err(|e| {
+        error!("Couldn't get cast viewer context {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    render_casts(&state, feed.casts_mut(), render.render).await.map_err(|e| {
+        error!("Couldn't render casts {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let last_modified = LastModified::newest(feed.casts.iter().map(|timeline_cast| timeline_cast.cast.timestamp));
+    Ok((last_modified, Json(feed)))
+}
+
+async fn follow_channel(
+    State(state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    update_channel_follow(&state, viewer.fid, &id, true).await
+}
+
+async fn unfollow_channel(
+    State(state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    update_channel_follow(&state, viewer.fid, &id, false).await
+}
+
+async fn update_channel_follow(state: &ServiceState, fid: u64, id: &str, following: bool) -> Result<StatusCode, StatusCode> {
+    let channel = get_known_channel(state, id).await?;
+    state.set_channel_follow(fid, &channel.id, following).await.map_err(|e| {
+        error!("Couldn't update channel follow {fid} -> {id} {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_known_channel(state: &ServiceState, id: &str) -> Result<Channel, StatusCode> {
+    match state.get_channel(id).await {
+        Ok(Some(channel)) => Ok(channel),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Couldn't get channel {id} {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn get_followed_channels(
+    State(state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
+) -> Result<Json<Vec<Channel>>, StatusCode> {
+    match state.get_followed_channels(viewer.fid).await {
+        Ok(channels) => Ok(Json(channels)),
+        Err(e) => {
+            error!("Couldn't get followed channels for {} {e}", viewer.fid);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn get_suggestions(
+    State(state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
+    Query(page): Query<PageQuery>,
+) -> Result<Json<Vec<Suggestion>>, StatusCode> {
+
+    queue_index_links(&state.task_queue, viewer.fid)?;
+
+    match state.get_suggestions(viewer.fid, page.limit()).await {
+        Ok(suggestions) => {
+            Ok(Json(suggestions))
+        },
+        Err(e) => {
+            error!("Couldn't get suggestions for {} {e}", viewer.fid);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn search_users(
+    State(state): ServiceArcState,
+    Extension(viewer): Extension<Profile>,
+    Query(search): Query<UserSearchQuery>,
+) -> Result<Json<Vec<ViewerProfile>>, StatusCode> {
+    let q = search.q.trim();
+    if q.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let found = state.search_users(
+        viewer.fid,
+        q,
+        search.boost_following.unwrap_or(true),
+        page_limit(search.page_size)
+    ).await.map_err(|e| {
+        error!("Couldn't search users {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    match state.with_viewer_context(viewer.fid, found).await {
+        Ok(profiles) => {
+            Ok(Json(profiles))
+        },
+        Err(e) => {
+            error!("Couldn't get viewer context {e}");
             Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
@@ -0,0 +1,115 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::user_models::ViewerProfile;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct PageQuery {
+    pub page_size: Option<u32>,
+    pub page_token: Option<String>,
+}
+
+pub fn page_limit(page_size: Option<u32>) -> i64 {
+    page_size
+        .map(|size| (size as i64).clamp(1, MAX_PAGE_SIZE))
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+}
+
+impl PageQuery {
+    pub fn limit(&self) -> i64 {
+        page_limit(self.page_size)
+    }
+
+    pub fn cursor(&self) -> Result<Option<Cursor>> {
+        self.page_token.as_deref().map(Cursor::decode).transpose()
+    }
+}
+
+/**
+* Keyset position for lists ordered by (timestamp desc, key desc), handed to clients as an opaque hex token
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Cursor {
+    pub timestamp: i64,
+    pub key: Vec<u8>,
+}
+
+impl Cursor {
+    pub fn new(time: SystemTime, key: Vec<u8>) -> Self {
+        let timestamp = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as i64;
+        Cursor { timestamp, key }
+    }
+
+    pub fn for_fid(time: SystemTime, fid: i64) -> Self {
+        Self::new(time, fid.to_be_bytes().to_vec())
+    }
+
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(self.timestamp.max(0) as u64)
+    }
+
+    pub fn fid(&self) -> i64 {
+        let mut bytes = [0u8; 8];
+        let len = self.key.len().min(8);
+        bytes[8 - len..].copy_from_slice(&self.key[..len]);
+        i64::from_be_bytes(bytes)
+    }
+
+    pub fn encode(&self) -> String {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(self, &mut bytes).expect("Couldn't encode cursor");
+        hex::encode(bytes)
+    }
+
+    pub fn decode(token: &str) -> Result<Self> {
+        let bytes = hex::decode(token)?;
+        Ok(ciborium::from_reader(bytes.as_slice())?)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProfilePage {
+    pub profiles: Vec<ViewerProfile>,
+    pub next_page: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trips_through_a_token() {
+        let cursor = Cursor::new(UNIX_EPOCH + Duration::from_micros(1_723_000_000_123_456), vec![1, 2, 0xff]);
+        let decoded = Cursor::decode(&cursor.encode()).unwrap();
+        assert_eq!(decoded, cursor);
+        assert_eq!(decoded.time(), UNIX_EPOCH + Duration::from_micros(1_723_000_000_123_456));
+    }
+
+    #[test]
+    fn cursor_rejects_garbage_tokens() {
+        assert!(Cursor::decode("not hex").is_err());
+        assert!(Cursor::decode("00ff").is_err());
+    }
+
+    #[test]
+    fn cursor_fid_key() {
+        let cursor = Cursor::for_fid(UNIX_EPOCH, 4_321);
+        assert_eq!(cursor.fid(), 4_321);
+        // short keys are read as the low bytes
+        assert_eq!(Cursor::new(UNIX_EPOCH, vec![1, 0]).fid(), 256);
+        assert_eq!(Cursor::new(UNIX_EPOCH, vec![]).fid(), 0);
+    }
+
+    #[test]
+    fn page_size_is_clamped() {
+        let limit = |page_size| PageQuery { page_size, page_token: None }.limit();
+        assert_eq!(limit(None), DEFAULT_PAGE_SIZE);
+        assert_eq!(limit(Some(0)), 1);
+        assert_eq!(limit(Some(10_000)), MAX_PAGE_SIZE);
+    }
+}
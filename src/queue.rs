@@ -0,0 +1,512 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use diesel::prelude::*;
+use diesel::PgConnection;
+use diesel::result::{DatabaseErrorKind, Error::DatabaseError};
+use diesel::sql_types::{Array, BigInt, Binary, Bool, Int2, Int4, Text};
+use eyre::{bail, Result};
+use rand::Rng;
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::schema::dead_tasks::dsl::{dead_tasks, failed_at, task_id as dead_task_id};
+use crate::schema::task_queue::dsl::{leased_until, task_id, task_queue};
+use crate::error::ServerError;
+use crate::service::DbPool;
+use crate::crawl::Hop;
+use crate::worker::{Origin, Priority, Task, TaskEnvelope};
+
+// tasks are dead lettered after failing or crashing their worker this many times
+pub const MAX_ATTEMPTS: i32 = 5;
+const BASE_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+// how long a leased task stays invisible to other consumers before it's handed out again
+const VISIBILITY_TIMEOUT_SECS: i32 = 5 * 60;
+/// how often a worker renews the leases of tasks it's holding, well within the visibility timeout
+pub const LEASE_RENEW_INTERVAL: Duration = Duration::from_secs(VISIBILITY_TIMEOUT_SECS as u64 / 3);
+// share of every lease reserved for background tasks, so a busy interactive lane can't starve the crawl
+const BACKGROUND_SHARE_PERCENT: usize = 20;
+// how stale the queue length checked against capacity may get, enqueues in between are counted locally
+const CAPACITY_REFRESH: Duration = Duration::from_secs(5);
+
+// claim the oldest ready tasks in a lane, skipping anything another process has locked or leased
+const LEASE_QUERY: &'static str = r#"
+UPDATE task_queue
+SET leased_until = now() + $2 * interval '1 second', attempts = attempts + 1
+WHERE task_id IN (
+    SELECT task_id FROM task_queue
+    WHERE priority = $4
+      AND run_after <= now()
+      AND (leased_until IS NULL OR leased_until < now())
+      AND attempts < $3
+    ORDER BY run_after, task_id
+    LIMIT $1
+    FOR UPDATE SKIP LOCKED
+)
+RETURNING task_id, task, attempts, priority, origin, seed_fid, depth
+"#;
+
+// only renews leases that are still held, an expired one may already be running elsewhere
+const RENEW_QUERY: &'static str = r#"
+UPDATE task_queue
+SET leased_until = now() + $2 * interval '1 second'
+WHERE task_id = ANY($1) AND leased_until >= now()
+"#;
+
+// a duplicate of a pending task is coalesced into it, bumping it to the more urgent lane, the shallower hop and the earlier start
+const ENQUEUE_QUERY: &'static str = r#"
+INSERT INTO task_queue (task_key, task, priority, origin, seed_fid, depth, run_after)
+VALUES ($1, $2, $3, $4, $5, $6, now() + $7 * interval '1 microsecond')
+ON CONFLICT (task_key) WHERE leased_until IS NULL
+DO UPDATE SET priority = least(task_queue.priority, excluded.priority),
+    seed_fid = CASE WHEN excluded.depth < task_queue.depth THEN excluded.seed_fid ELSE task_queue.seed_fid END,
+    depth = least(task_queue.depth, excluded.depth),
+    run_after = least(task_queue.run_after, excluded.run_after)
+RETURNING (xmax = 0) AS inserted
+"#;
+
+// the count is bounded so it stays cheap on a big backlog, and only rerun every CAPACITY_REFRESH
+const CAPACITY_QUERY: &'static str = r#"
+SELECT count(*) AS queued FROM (SELECT 1 FROM task_queue LIMIT $1) capped
+"#;
+
+// moves matching rows out of the queue and into dead_tasks in one statement
+const DEAD_LETTER_QUERY: &'static str = r#"
+WITH moved AS (
+    DELETE FROM task_queue WHERE task_id = $1
+    RETURNING task_id, task_key, task, attempts
+)
+INSERT INTO dead_tasks (task_id, task_key, task, attempts, last_error)
+SELECT task_id, task_key, task, attempts, $2 FROM moved
+ON CONFLICT (task_id) DO NOTHING
+"#;
+
+// leases that expired at max attempts mean the task never got to report back
+const DEAD_LETTER_EXHAUSTED_QUERY: &'static str = r#"
+WITH moved AS (
+    DELETE FROM task_queue WHERE attempts >= $1 AND leased_until < now()
+    RETURNING task_id, task_key, task, attempts
+)
+INSERT INTO dead_tasks (task_id, task_key, task, attempts, last_error)
+SELECT task_id, task_key, task, attempts, 'lease expired without completing' FROM moved
+ON CONFLICT (task_id) DO NOTHING
+"#;
+
+// the key prefix up to the first ':' is the task kind's name
+const PENDING_COUNTS_QUERY: &'static str = r#"
+SELECT split_part(task_key, ':', 1) AS kind, priority,
+    count(*) FILTER (WHERE leased_until IS NULL OR leased_until < now()) AS pending,
+    count(*) FILTER (WHERE leased_until >= now()) AS leased
+FROM task_queue
+GROUP BY 1, 2
+ORDER BY 1, 2
+"#;
+
+// the dead row is only removed once its task is back in the queue, a pending duplicate leaves it in place
+const REPLAY_QUERY: &'static str = r#"
+WITH dead AS (
+    SELECT task_key, task FROM dead_tasks WHERE task_id = $1
+), replayed AS (
+    INSERT INTO task_queue (task_key, task, priority, origin)
+    SELECT task_key, task, $2, $3 FROM dead
+    ON CONFLICT DO NOTHING
+    RETURNING task_id
+), removed AS (
+    DELETE FROM dead_tasks WHERE task_id = $1 AND EXISTS (SELECT 1 FROM replayed)
+    RETURNING task_id
+)
+SELECT EXISTS (SELECT 1 FROM dead) AS found, EXISTS (SELECT 1 FROM removed) AS replayed
+"#;
+
+// retries are scheduled on the database clock, like enqueued delays
+const RETRY_QUERY: &'static str = r#"
+UPDATE task_queue
+SET leased_until = NULL, run_after = now() + $2 * interval '1 microsecond'
+WHERE task_id = $1
+"#;
+
+#[derive(QueryableByName, Debug)]
+struct QueuedCount {
+    #[diesel(sql_type = BigInt)]
+    queued: i64,
+}
+
+/// Queued tasks of one kind in one lane
+#[derive(QueryableByName, Serialize, Debug)]
+pub struct KindCount {
+    #[diesel(sql_type = Text)]
+    pub kind: String,
+    #[diesel(sql_type = Int2)]
+    pub priority: i16,
+    #[diesel(sql_type = BigInt)]
+    pub pending: i64,
+    #[diesel(sql_type = BigInt)]
+    pub leased: i64,
+}
+
+#[derive(QueryableByName, Debug)]
+struct ReplayRow {
+    #[diesel(sql_type = Bool)]
+    found: bool,
+    #[diesel(sql_type = Bool)]
+    replayed: bool,
+}
+
+/// What replaying a dead task did
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Replay {
+    Queued,
+    /// an identical task is already pending, the dead task was left where it is
+    AlreadyPending,
+    NotFound,
+}
+
+#[derive(QueryableByName, Debug)]
+struct Enqueued {
+    #[diesel(sql_type = Bool)]
+    inserted: bool,
+}
+
+#[derive(QueryableByName, Debug)]
+#[diesel(table_name=crate::schema::task_queue)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+struct LeasedRow {
+    task_id: i64,
+    task: Vec<u8>,
+    attempts: i32,
+    priority: i16,
+    origin: String,
+    seed_fid: i64,
+    depth: i32,
+}
+
+/// A task leased from the queue, must be completed before `leased_until` or it becomes visible again
+#[derive(Debug, Clone)]
+pub struct QueuedTask {
+    pub task_id: i64,
+    pub task: Task,
+    pub attempts: i32,
+    pub priority: Priority,
+    pub origin: Origin,
+    pub hop: Hop,
+}
+
+/// A task that ran out of attempts, kept for inspection and replay
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name=crate::schema::dead_tasks)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DeadTask {
+    pub task_id: i64,
+    pub task_key: String,
+    pub attempts: i32,
+    pub last_error: String,
+    pub failed_at: SystemTime,
+}
+
+/**
+* exponential backoff from BASE_BACKOFF, capped at MAX_BACKOFF, with +/- 25% jitter so retries don't line up
+*/
+fn backoff(attempt: i32) -> Duration {
+    let exponent = attempt.saturating_sub(1).clamp(0, 16) as u32;
+    let delay = BASE_BACKOFF.saturating_mul(2u32.pow(exponent)).min(MAX_BACKOFF);
+    delay.mul_f64(rand::thread_rng().gen_range(0.75..=1.25))
+}
+
+// how much of a batch goes to the background lane first, batches too small for a share take turns instead
+fn background_reserve(batch: usize, round: usize) -> usize {
+    let reserved = batch * BACKGROUND_SHARE_PERCENT / 100;
+    if reserved == 0 && batch > 0 && round % (100 / BACKGROUND_SHARE_PERCENT) == 0 {
+        return 1;
+    }
+    reserved
+}
+
+/**
+* Durable work queue backed by the task_queue table, safe to consume from several server processes
+*/
+#[derive(Clone)]
+pub struct TaskQueue {
+    db_pool: DbPool,
+    capacity: i64,
+    lease_rounds: Arc<AtomicUsize>,
+    length: Arc<Mutex<QueueLength>>,
+}
+
+// what the queue length was last counted at, plus what this process has added since
+struct QueueLength {
+    queued: i64,
+    counted: Option<Instant>,
+}
+
+fn encode_task(task: &Task) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    ciborium::into_writer(task, &mut bytes)?;
+    Ok(bytes)
+}
+
+impl TaskQueue {
+    pub fn new(db_pool: DbPool, capacity: i64) -> Self {
+        TaskQueue {
+            db_pool,
+            capacity,
+            lease_rounds: Arc::new(AtomicUsize::new(0)),
+            length: Arc::new(Mutex::new(QueueLength {
+                queued: 0,
+                counted: None,
+            })),
+        }
+    }
+
+    // other processes' enqueues and every lease show up at the next recount
+    fn approximate_length(&self, db: &mut PgConnection) -> Result<i64> {
+        {
+            let mut length = self.length.lock().unwrap();
+            if length.counted.is_some_and(|counted| counted.elapsed() <= CAPACITY_REFRESH) {
+                return Ok(length.queued);
+            }
+            // claim the recount so concurrent enqueues keep using the last count meanwhile
+            length.counted = Some(Instant::now());
+        }
+        let count = diesel::sql_query(CAPACITY_QUERY)
+            .bind::<BigInt, _>(self.capacity)
+            .get_result::<QueuedCount>(db);
+        let mut length = self.length.lock().unwrap();
+        match count {
+            Ok(count) => {
+                length.queued = count.queued;
+                Ok(length.queued)
+            }
+            Err(e) => {
+                length.counted = None;
+                Err(e.into())
+            }
+        }
+    }
+
+    /**
+    * returns false if an identical task was already pending and this one was coalesced into it,
+    * fails with ServerError::QueueFull once about `capacity` tasks are waiting.
+    * Hub events are always taken, the subscription won't deliver them again
+    */
+    pub fn enqueue(&self, envelope: TaskEnvelope) -> Result<bool> {
+        let mut db = self.db_pool.get()?;
+        if envelope.origin != Origin::Subscriber && self.approximate_length(&mut db)? >= self.capacity {
+            bail!(ServerError::QueueFull);
+        }
+
+        let enqueued = diesel::sql_query(ENQUEUE_QUERY)
+            .bind::<Text, _>(envelope.task.key())
+            .bind::<Binary, _>(encode_task(&envelope.task)?)
+            .bind::<Int2, _>(envelope.priority.lane())
+            .bind::<Text, _>(envelope.origin.name())
+            .bind::<BigInt, _>(envelope.hop.seed as i64)
+            .bind::<Int4, _>(envelope.hop.depth as i32)
+            .bind::<BigInt, _>(envelope.delay.as_micros().min(i64::MAX as u128) as i64)
+            .get_result::<Enqueued>(&mut db)?;
+        if enqueued.inserted {
+            self.length.lock().unwrap().queued += 1;
+        }
+        Ok(enqueued.inserted)
+    }
+
+    /**
+    * leases up to `batch` tasks, interactive ones first except for a share reserved for background work.
+    * Lanes that come up short leave their slots to the other one.
+    */
+    pub fn lease(&self, batch: i64) -> Result<Vec<QueuedTask>> {
+        let batch = batch.max(0) as usize;
+        let round = self.lease_rounds.fetch_add(1, Ordering::Relaxed);
+        let reserved = background_reserve(batch, round);
+
+        let mut leased = self.lease_lane(Priority::Interactive, batch - reserved)?;
+        leased.extend(self.lease_lane(Priority::Background, batch - leased.len())?);
+        if leased.len() < batch {
+            leased.extend(self.lease_lane(Priority::Interactive, batch - leased.len())?);
+        }
+        Ok(leased)
+    }
+
+    fn lease_lane(&self, priority: Priority, batch: usize) -> Result<Vec<QueuedTask>> {
+        if batch == 0 {
+            return Ok(vec![]);
+        }
+        let mut db = self.db_pool.get()?;
+        let rows = diesel::sql_query(LEASE_QUERY)
+            .bind::<BigInt, _>(batch as i64)
+            .bind::<Int4, _>(VISIBILITY_TIMEOUT_SECS)
+            .bind::<Int4, _>(MAX_ATTEMPTS)
+            .bind::<Int2, _>(priority.lane())
+            .load::<LeasedRow>(&mut db)?;
+
+        let mut leased = Vec::with_capacity(rows.len());
+        for row in rows {
+            match ciborium::from_reader::<Task, _>(row.task.as_slice()) {
+                Ok(task) => leased.push(QueuedTask {
+                    task_id: row.task_id,
+                    task,
+                    attempts: row.attempts,
+                    priority: Priority::from_lane(row.priority),
+                    origin: Origin::from_name(&row.origin),
+                    hop: Hop {
+                        seed: row.seed_fid as u64,
+                        depth: row.depth.max(0) as u32
+                    },
+                }),
+                Err(e) => {
+                    // nothing will ever be able to run it, set it aside rather than lease it forever
+                    error!("Couldn't decode queued task {}, dead lettering {e}", row.task_id);
+                    self.dead_letter(row.task_id, &format!("couldn't decode task: {e}"))?;
+                }
+            }
+        }
+        Ok(leased)
+    }
+
+    /// keeps tasks that are waiting on or holding a worker permit from being handed out again, returns how many were renewed
+    pub fn renew(&self, ids: &[i64]) -> Result<usize> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let mut db = self.db_pool.get()?;
+        Ok(diesel::sql_query(RENEW_QUERY)
+            .bind::<Array<BigInt>, _>(ids)
+            .bind::<Int4, _>(VISIBILITY_TIMEOUT_SECS)
+            .execute(&mut db)?)
+    }
+
+    pub fn complete(&self, id: i64) -> Result<()> {
+        let mut db = self.db_pool.get()?;
+        diesel::delete(task_queue.filter(task_id.eq(id))).execute(&mut db)?;
+        Ok(())
+    }
+
+    /**
+    * schedules a failed task to run again after a backoff, or dead letters it once it's out of attempts
+    */
+    pub fn fail(&self, queued: QueuedTask, reason: &str) -> Result<()> {
+        if queued.attempts >= MAX_ATTEMPTS {
+            warn!("Task {:?} failed {} times, dead lettering", queued.task, queued.attempts);
+            return self.dead_letter(queued.task_id, reason);
+        }
+
+        let delay = backoff(queued.attempts);
+        let mut db = self.db_pool.get()?;
+        let retried = diesel::sql_query(RETRY_QUERY)
+            .bind::<BigInt, _>(queued.task_id)
+            .bind::<BigInt, _>(delay.as_micros().min(i64::MAX as u128) as i64)
+            .execute(&mut db);
+
+        match retried {
+            Ok(_) => {
+                warn!("Task {:?} failed on attempt {}, retrying in {delay:?}: {reason}", queued.task, queued.attempts);
+                Ok(())
+            }
+            // an identical task was queued in the meantime, it will do the retry for us
+            Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => self.complete(queued.task_id),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    /// removes a task that hasn't been leased yet, false if it's running or already gone
+    pub fn cancel(&self, id: i64) -> Result<bool> {
+        let mut db = self.db_pool.get()?;
+        let deleted = diesel::delete(task_queue.filter(task_id.eq(id)))
+            .filter(leased_until.is_null().or(leased_until.lt(SystemTime::now())))
+            .execute(&mut db)?;
+        Ok(deleted > 0)
+    }
+
+    pub fn pending_counts(&self) -> Result<Vec<KindCount>> {
+        let mut db = self.db_pool.get()?;
+        Ok(diesel::sql_query(PENDING_COUNTS_QUERY).load(&mut db)?)
+    }
+
+    pub fn dead_letter(&self, id: i64, reason: &str) -> Result<()> {
+        let mut db = self.db_pool.get()?;
+        diesel::sql_query(DEAD_LETTER_QUERY)
+            .bind::<BigInt, _>(id)
+            .bind::<Text, _>(reason)
+            .execute(&mut db)?;
+        Ok(())
+    }
+
+    /// dead letters tasks that have crashed their worker too many times
+    pub fn dead_letter_exhausted(&self) -> Result<usize> {
+        let mut db = self.db_pool.get()?;
+        Ok(diesel::sql_query(DEAD_LETTER_EXHAUSTED_QUERY)
+            .bind::<Int4, _>(MAX_ATTEMPTS)
+            .execute(&mut db)?)
+    }
+
+    pub fn dead_tasks(&self, limit: i64) -> Result<Vec<DeadTask>> {
+        let mut db = self.db_pool.get()?;
+        Ok(dead_tasks.select(DeadTask::as_select())
+            .order((failed_at.desc(), dead_task_id.desc()))
+            .limit(limit)
+            .load(&mut db)?)
+    }
+
+    /// moves a dead task back into the queue with fresh attempts
+    pub fn replay(&self, id: i64) -> Result<Replay> {
+        let mut db = self.db_pool.get()?;
+        let row = diesel::sql_query(REPLAY_QUERY)
+            .bind::<BigInt, _>(id)
+            .bind::<Int2, _>(Origin::Replay.default_priority().lane())
+            .bind::<Text, _>(Origin::Replay.name())
+            .get_result::<ReplayRow>(&mut db)?;
+        Ok(match (row.found, row.replayed) {
+            (false, _) => Replay::NotFound,
+            (true, true) => Replay::Queued,
+            (true, false) => Replay::AlreadyPending,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_within_jitter() {
+        for attempt in 1..=4 {
+            let base = BASE_BACKOFF * 2u32.pow(attempt as u32 - 1);
+            let delay = backoff(attempt);
+            assert!(delay >= base.mul_f64(0.75) && delay <= base.mul_f64(1.25), "attempt {attempt} waited {delay:?}");
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        for attempt in [12, 100, i32::MAX] {
+            assert!(backoff(attempt) <= MAX_BACKOFF.mul_f64(1.25));
+            assert!(backoff(attempt) >= MAX_BACKOFF.mul_f64(0.75));
+        }
+    }
+
+    #[test]
+    fn backoff_before_the_first_attempt() {
+        for attempt in [0, -1] {
+            let delay = backoff(attempt);
+            assert!(delay >= BASE_BACKOFF.mul_f64(0.75) && delay <= BASE_BACKOFF.mul_f64(1.25));
+        }
+    }
+
+    #[test]
+    fn background_lane_gets_its_share() {
+        assert_eq!(background_reserve(32, 1), 6);
+        assert_eq!(background_reserve(10, 3), 2);
+        assert_eq!(background_reserve(0, 0), 0);
+    }
+
+    #[test]
+    fn small_batches_take_turns() {
+        let rounds = 100 / BACKGROUND_SHARE_PERCENT;
+        let reserved = (0..rounds * 3).map(|round| background_reserve(2, round)).collect::<Vec<_>>();
+        assert_eq!(reserved.iter().sum::<usize>(), 3);
+        assert!(reserved.iter().all(|r| *r <= 1));
+        assert_eq!(background_reserve(1, 0), 1);
+        assert_eq!(background_reserve(1, 1), 0);
+    }
+}
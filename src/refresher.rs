@@ -0,0 +1,46 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{debug, error};
+
+use crate::service::ServiceState;
+use crate::suggestion_repo::SuggestionRepository;
+use crate::timeline_repo::TimelineRepository;
+
+/**
+* Periodically rebuilds derived tables (follow suggestions) and trims home timelines in the background
+*/
+pub struct Refresher {
+    handle: JoinHandle<()>
+}
+
+async fn refresh_loop(service_state: Arc<ServiceState>, period: Duration) {
+    let mut ticker = interval(period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        match service_state.refresh_suggestions().await {
+            Ok(_) => debug!("Refreshed follow suggestions"),
+            Err(e) => error!("Error refreshing follow suggestions {e}")
+        }
+        match service_state.trim_home_timelines().await {
+            Ok(trimmed) => debug!("Trimmed {trimmed} home timeline entries"),
+            Err(e) => error!("Error trimming home timelines {e}")
+        }
+    }
+}
+
+impl Refresher {
+    pub fn new(service_state: Arc<ServiceState>, period: Duration) -> Self {
+        let handle = tokio::spawn(refresh_loop(service_state, period));
+        Refresher {
+            handle
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.handle.abort();
+    }
+}
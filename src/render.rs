@@ -0,0 +1,280 @@
+use std::collections::{HashMap, HashSet};
+
+use eyre::Result;
+use fatline_rs::users::Profile;
+use serde::{Deserialize, Serialize};
+
+use crate::cast_models::CastView;
+use crate::service::ServiceState;
+use crate::user_repo::UserRepository;
+
+// trimmed off the end of a url, they're almost always the sentence around it
+const URL_TRAILING: &[char] = &['.', ',', ';', ':', '!', '?', '\'', '"'];
+
+/// A run of cast text, with mentions spliced back in and urls picked out
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TextSegment {
+    Text {
+        text: String,
+    },
+    Mention {
+        fid: u64,
+        /// what to show, @username when we know it
+        text: String,
+        profile: Option<Profile>,
+    },
+    Url {
+        url: String,
+    },
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TextFormat {
+    Html,
+    Markdown,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct RenderQuery {
+    /// also render each cast's text in this format
+    pub render: Option<TextFormat>,
+}
+
+// largest char boundary at or before `position`, hub offsets can point past the text or into a char
+fn floor_boundary(text: &str, position: usize) -> usize {
+    let mut position = position.min(text.len());
+    while !text.is_char_boundary(position) {
+        position -= 1;
+    }
+    position
+}
+
+fn url_end(text: &str) -> usize {
+    let mut end = text.find(char::is_whitespace).unwrap_or(text.len());
+    loop {
+        let url = &text[..end];
+        let trimmed = url.trim_end_matches(URL_TRAILING);
+        // keeps the closing paren of a url like wiki/Rust_(language)
+        let trimmed = if trimmed.ends_with(')') && trimmed.matches('(').count() < trimmed.matches(')').count() {
+            &trimmed[..trimmed.len() - 1]
+        } else {
+            trimmed
+        };
+        if trimmed.len() == url.len() {
+            return end;
+        }
+        end = trimmed.len();
+    }
+}
+
+// splits a run of plain text on the urls in it
+fn push_text(segments: &mut Vec<TextSegment>, mut text: &str) {
+    while !text.is_empty() {
+        let found = ["https://", "http://"].iter()
+            .filter_map(|scheme| text.find(scheme).map(|start| (start, scheme.len())))
+            .min();
+        let Some((start, scheme_len)) = found else { break };
+        let end = start + url_end(&text[start..]);
+        // nothing after the scheme isn't a link
+        if end <= start + scheme_len {
+            segments.push(TextSegment::Text { text: text[..end].to_string() });
+            text = &text[end..];
+            continue;
+        }
+        if start > 0 {
+            segments.push(TextSegment::Text { text: text[..start].to_string() });
+        }
+        segments.push(TextSegment::Url { url: text[start..end].to_string() });
+        text = &text[end..];
+    }
+    if !text.is_empty() {
+        segments.push(TextSegment::Text { text: text.to_string() });
+    }
+}
+
+/**
+* Splits cast text into segments, inserting a mention at each of `positions`. Positions are byte offsets
+* into the UTF-8 text, as the hub sends them, and the text doesn't contain the mentions themselves.
+*/
+pub fn segments(text: &str, mentions: &[u64], positions: &[u32], profiles: &HashMap<u64, Profile>) -> Vec<TextSegment> {
+    let mut spliced = mentions.iter().copied()
+        .zip(positions.iter().map(|position| floor_boundary(text, *position as usize)))
+        .collect::<Vec<_>>();
+    spliced.sort_by_key(|(_, position)| *position);
+
+    let mut segments = Vec::new();
+    let mut cursor = 0;
+    for (fid, position) in spliced {
+        push_text(&mut segments, &text[cursor..position]);
+        let profile = profiles.get(&fid).cloned();
+        let shown = match profile.as_ref().and_then(|profile| profile.username.as_ref()) {
+            Some(username) => format!("@{username}"),
+            // how farcaster clients show an fid without a username
+            None => format!("@!{fid}"),
+        };
+        segments.push(TextSegment::Mention { fid, text: shown, profile });
+        cursor = position;
+    }
+    push_text(&mut segments, &text[cursor..]);
+    segments
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            '\n' => escaped.push_str("<br>"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '#' | '~' | '|') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+pub fn render(segments: &[TextSegment], format: TextFormat) -> String {
+    segments.iter().map(|segment| match (format, segment) {
+        (TextFormat::Html, TextSegment::Text { text }) => escape_html(text),
+        (TextFormat::Html, TextSegment::Mention { fid, text, .. }) =>
+            format!("<a href=\"/profile/{fid}\" class=\"mention\">{}</a>", escape_html(text)),
+        (TextFormat::Html, TextSegment::Url { url }) =>
+            format!("<a href=\"{0}\" rel=\"nofollow noopener\">{0}</a>", escape_html(url)),
+        (TextFormat::Markdown, TextSegment::Text { text }) => escape_markdown(text),
+        (TextFormat::Markdown, TextSegment::Mention { fid, text, .. }) =>
+            format!("[{}](/profile/{fid})", escape_markdown(text)),
+        (TextFormat::Markdown, TextSegment::Url { url }) =>
+            format!("[{}]({})", escape_markdown(url), url.replace('(', "%28").replace(')', "%29")),
+    }).collect()
+}
+
+/// fills in segments, and `format` if asked for, for every cast on a page, with one profile lookup
+pub async fn render_casts(state: &ServiceState, cast_views: Vec<&mut CastView>, format: Option<TextFormat>) -> Result<()> {
+    let fids = cast_views.iter()
+        .flat_map(|cast| cast.mentions.iter().copied())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let profiles = if fids.is_empty() {
+        HashMap::new()
+    } else {
+        state.get_stored_profiles(&fids).await?
+    };
+
+    for cast in cast_views {
+        let segments = segments(&cast.text, &cast.mentions, &cast.mentions_positions, &profiles);
+        cast.rendered = format.map(|format| render(&segments, format));
+        cast.segments = segments;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn describe(segments: &[TextSegment]) -> Vec<String> {
+        segments.iter().map(|segment| match segment {
+            TextSegment::Text { text } => format!("text:{text}"),
+            TextSegment::Mention { fid, text, .. } => format!("mention:{fid}:{text}"),
+            TextSegment::Url { url } => format!("url:{url}"),
+        }).collect()
+    }
+
+    fn profile(fid: u64, username: &str) -> Profile {
+        Profile {
+            fid,
+            url: None,
+            username: Some(username.to_string()),
+            bio: None,
+            display_name: None,
+            profile_picture: None,
+        }
+    }
+
+    #[test]
+    fn mention_after_multibyte_text() {
+        let profiles = HashMap::from([(3, profile(3, "alice"))]);
+        let segments = segments("héllo  there", &[3], &[7], &profiles);
+        assert_eq!(describe(&segments), ["text:héllo ", "mention:3:@alice", "text: there"]);
+    }
+
+    #[test]
+    fn mention_inside_code_point_moves_to_its_start() {
+        let segments = segments("é!", &[5], &[1], &HashMap::new());
+        assert_eq!(describe(&segments), ["mention:5:@!5", "text:é!"]);
+    }
+
+    #[test]
+    fn mention_past_the_end_goes_last() {
+        let segments = segments("hi", &[5], &[100], &HashMap::new());
+        assert_eq!(describe(&segments), ["text:hi", "mention:5:@!5"]);
+    }
+
+    #[test]
+    fn mentions_at_the_same_position_keep_their_order() {
+        let segments = segments("a  b", &[2, 1], &[2, 2], &HashMap::new());
+        assert_eq!(describe(&segments), ["text:a ", "mention:2:@!2", "mention:1:@!1", "text: b"]);
+    }
+
+    #[test]
+    fn url_trailing_punctuation_and_parens() {
+        let segments = segments("see https://en.wikipedia.org/wiki/Rust_(language). (https://example.com)", &[], &[], &HashMap::new());
+        assert_eq!(describe(&segments), [
+            "text:see ",
+            "url:https://en.wikipedia.org/wiki/Rust_(language)",
+            "text:. (",
+            "url:https://example.com",
+            "text:)",
+        ]);
+    }
+
+    #[test]
+    fn bare_scheme_is_text() {
+        let segments = segments("https:// nothing", &[], &[], &HashMap::new());
+        assert_eq!(describe(&segments), ["text:https://", "text: nothing"]);
+    }
+
+    #[test]
+    fn html_escapes_text_and_urls() {
+        let segments = vec![
+            TextSegment::Text { text: "a < b & \"c\"".to_string() },
+            TextSegment::Url { url: "https://x.com/?a=<b>&c=\"1\"".to_string() },
+        ];
+        assert_eq!(
+            render(&segments, TextFormat::Html),
+            "a &lt; b &amp; &quot;c&quot;\
+             <a href=\"https://x.com/?a=&lt;b&gt;&amp;c=&quot;1&quot;\" rel=\"nofollow noopener\">https://x.com/?a=&lt;b&gt;&amp;c=&quot;1&quot;</a>"
+        );
+    }
+
+    #[test]
+    fn markdown_escapes_text_mentions_and_urls() {
+        let segments = vec![
+            TextSegment::Text { text: "*bold* _x_ [y] ".to_string() },
+            TextSegment::Mention { fid: 1, text: "@al_ice".to_string(), profile: None },
+            TextSegment::Text { text: " ".to_string() },
+            TextSegment::Url { url: "https://en.wikipedia.org/wiki/Rust_(language)".to_string() },
+        ];
+        assert_eq!(
+            render(&segments, TextFormat::Markdown),
+            "\\*bold\\* \\_x\\_ \\[y\\] [@al\\_ice](/profile/1) \
+             [https://en.wikipedia.org/wiki/Rust\\_(language)](https://en.wikipedia.org/wiki/Rust_%28language%29)"
+        );
+    }
+}
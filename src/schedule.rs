@@ -0,0 +1,174 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use dotenvy::var;
+use serde::{Deserialize, Serialize};
+
+use crate::worker::{Task, TaskKind};
+
+/// What a task's `force` flag does to its interval
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForceMode {
+    /// forced tasks run even if the interval hasn't passed
+    Bypass,
+    /// forced tasks are throttled like any other
+    Ignore,
+}
+
+impl FromStr for ForceMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "bypass" => Ok(ForceMode::Bypass),
+            "ignore" => Ok(ForceMode::Ignore),
+            _ => Err(())
+        }
+    }
+}
+
+/// How often a task kind may run again for the same target, 0 runs every time
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct KindSchedule {
+    pub interval_secs: u64,
+    pub force: ForceMode,
+}
+
+impl KindSchedule {
+    const fn new(interval_secs: u64, force: ForceMode) -> Self {
+        KindSchedule {
+            interval_secs,
+            force
+        }
+    }
+
+    fn default_for(kind: TaskKind) -> Self {
+        match kind {
+            TaskKind::IndexFid => KindSchedule::new(5 * 60, ForceMode::Bypass),
+            TaskKind::IndexLinks => KindSchedule::new(30 * 60, ForceMode::Bypass),
+            TaskKind::IndexFidCasts => KindSchedule::new(10 * 60, ForceMode::Bypass),
+            // casts can't change once they're indexed, only their replies can
+            TaskKind::IndexCast => KindSchedule::new(60 * 60, ForceMode::Ignore),
+            TaskKind::IndexChannel => KindSchedule::new(5 * 60, ForceMode::Ignore),
+            TaskKind::UpdateSigner | TaskKind::UpdateLink | TaskKind::UpdateCast | TaskKind::UpdateReaction =>
+                KindSchedule::new(0, ForceMode::Ignore),
+        }
+    }
+
+    pub fn throttled(&self) -> bool {
+        self.interval_secs > 0
+    }
+
+    /// whether a task last run at `last_run` should run again at `now`
+    pub fn should_run(&self, task: &Task, last_run: u64, now: u64) -> bool {
+        if task.forced() && self.force == ForceMode::Bypass {
+            return true;
+        }
+        now >= last_run.saturating_add(self.interval_secs)
+    }
+}
+
+/**
+* Intervals and force semantics for every task kind, read from SCHEDULE_INTERVAL_<KIND> (seconds)
+* and SCHEDULE_FORCE_<KIND> ("bypass" or "ignore"), eg SCHEDULE_INTERVAL_INDEX_LINKS
+*/
+#[derive(Debug, Clone)]
+pub struct SchedulePolicy {
+    kinds: HashMap<TaskKind, KindSchedule>,
+}
+
+/// The policy shared between the worker and the admin endpoints
+pub type SharedSchedule = Arc<RwLock<SchedulePolicy>>;
+
+impl SchedulePolicy {
+    pub fn from_env() -> Self {
+        let kinds = TaskKind::ALL.iter().map(|kind| {
+            let name = kind.name().to_uppercase();
+            let default = KindSchedule::default_for(*kind);
+            let schedule = KindSchedule {
+                interval_secs: var(format!("SCHEDULE_INTERVAL_{name}")).ok()
+                    .and_then(|secs| u64::from_str(&secs).ok())
+                    .unwrap_or(default.interval_secs),
+                force: var(format!("SCHEDULE_FORCE_{name}")).ok()
+                    .and_then(|force| ForceMode::from_str(&force).ok())
+                    .unwrap_or(default.force),
+            };
+            (*kind, schedule)
+        }).collect();
+        SchedulePolicy {
+            kinds
+        }
+    }
+
+    pub fn shared(self) -> SharedSchedule {
+        Arc::new(RwLock::new(self))
+    }
+
+    pub fn get(&self, kind: TaskKind) -> KindSchedule {
+        self.kinds.get(&kind).copied().unwrap_or(KindSchedule::default_for(kind))
+    }
+
+    pub fn set(&mut self, kind: TaskKind, schedule: KindSchedule) {
+        self.kinds.insert(kind, schedule);
+    }
+
+    /// last runs older than this can't throttle anything
+    pub fn longest_interval(&self) -> Duration {
+        Duration::from_secs(self.kinds.values().map(|schedule| schedule.interval_secs).max().unwrap_or(0))
+    }
+
+    /// the policy keyed by kind name, as served by the admin endpoints
+    pub fn entries(&self) -> BTreeMap<&'static str, KindSchedule> {
+        TaskKind::ALL.iter().map(|kind| (kind.name(), self.get(*kind))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_once_the_interval_has_passed() {
+        let schedule = KindSchedule::new(60, ForceMode::Bypass);
+        let task = Task::IndexFid(1, false);
+        assert!(!schedule.should_run(&task, 1_000, 1_059));
+        assert!(schedule.should_run(&task, 1_000, 1_060));
+        // a last run from the future, eg after a clock change, still throttles
+        assert!(!schedule.should_run(&task, 2_000, 1_060));
+    }
+
+    #[test]
+    fn zero_interval_always_runs() {
+        let schedule = KindSchedule::new(0, ForceMode::Ignore);
+        assert!(!schedule.throttled());
+        assert!(schedule.should_run(&Task::IndexFid(1, false), 1_000, 1_000));
+    }
+
+    #[test]
+    fn force_bypasses_or_is_ignored() {
+        let forced = Task::IndexFid(1, true);
+        assert!(KindSchedule::new(60, ForceMode::Bypass).should_run(&forced, 1_000, 1_001));
+        assert!(!KindSchedule::new(60, ForceMode::Ignore).should_run(&forced, 1_000, 1_001));
+        // tasks without a force flag are never forced
+        assert!(!KindSchedule::new(60, ForceMode::Bypass).should_run(&Task::IndexCast(1, vec![1]), 1_000, 1_001));
+    }
+
+    #[test]
+    fn force_mode_from_str() {
+        assert_eq!(ForceMode::from_str("bypass"), Ok(ForceMode::Bypass));
+        assert_eq!(ForceMode::from_str("ignore"), Ok(ForceMode::Ignore));
+        assert_eq!(ForceMode::from_str("Bypass"), Err(()));
+    }
+
+    #[test]
+    fn policy_overrides_defaults() {
+        let mut policy = SchedulePolicy { kinds: HashMap::new() };
+        assert_eq!(policy.get(TaskKind::IndexCast), KindSchedule::default_for(TaskKind::IndexCast));
+        policy.set(TaskKind::IndexCast, KindSchedule::new(5, ForceMode::Bypass));
+        assert_eq!(policy.get(TaskKind::IndexCast), KindSchedule::new(5, ForceMode::Bypass));
+        assert_eq!(policy.longest_interval(), Duration::from_secs(5));
+    }
+}
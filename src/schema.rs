@@ -1,13 +1,97 @@
 // @generated automatically by Diesel CLI.
 
 diesel::table! {
-    links (fid, target) {
+    cast_tombstones (hash) {
+        hash -> Bytea,
+        fid -> Int8,
+        parent_hash -> Nullable<Bytea>,
+        parent_fid -> Nullable<Int8>,
+        deleted_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    casts (hash) {
+        hash -> Bytea,
+        fid -> Int8,
+        text -> Text,
+        parent_hash -> Nullable<Bytea>,
+        parent_fid -> Nullable<Int8>,
+        parent_url -> Nullable<Text>,
+        embeds -> Bytea,
+        mentions -> Array<Int8>,
+        mentions_positions -> Array<Int4>,
+        timestamp -> Timestamp,
+    }
+}
+
+diesel::table! {
+    channel_follows (fid, channel_id) {
+        fid -> Int8,
+        channel_id -> Text,
+        timestamp -> Timestamp,
+    }
+}
+
+diesel::table! {
+    channels (id) {
+        id -> Text,
+        url -> Text,
+        name -> Text,
+        description -> Text,
+        image_url -> Nullable<Text>,
+        created -> Timestamp,
+    }
+}
+
+diesel::table! {
+    dead_tasks (task_id) {
+        task_id -> Int8,
+        task_key -> Text,
+        task -> Bytea,
+        attempts -> Int4,
+        last_error -> Text,
+        failed_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    feed_backfills (feed) {
+        feed -> Text,
+        completed -> Timestamp,
+    }
+}
+
+diesel::table! {
+    home_timeline (fid, sort_key) {
+        fid -> Int8,
+        sort_key -> Bytea,
+        cast_hash -> Bytea,
+        author_fid -> Int8,
+        recasted_by -> Nullable<Int8>,
+        is_reply -> Bool,
+        timestamp -> Timestamp,
+    }
+}
+
+diesel::table! {
+    link_removals (fid, target, link_type) {
         fid -> Int8,
         target -> Int8,
+        link_type -> Text,
         timestamp -> Timestamp,
     }
 }
 
+diesel::table! {
+    links (fid, target, link_type) {
+        fid -> Int8,
+        target -> Int8,
+        timestamp -> Timestamp,
+        link_type -> Text,
+    }
+}
+
 diesel::table! {
     notifications (notification_id) {
         notification_id -> Uuid,
@@ -19,6 +103,33 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    reaction_counts (hash) {
+        hash -> Bytea,
+        likes -> Int8,
+        recasts -> Int8,
+    }
+}
+
+diesel::table! {
+    reaction_removals (fid, target_hash, reaction_type) {
+        fid -> Int8,
+        target_hash -> Bytea,
+        reaction_type -> Int2,
+        timestamp -> Timestamp,
+    }
+}
+
+diesel::table! {
+    reactions (fid, target_hash, reaction_type) {
+        fid -> Int8,
+        target_hash -> Bytea,
+        reaction_type -> Int2,
+        target_fid -> Int8,
+        timestamp -> Timestamp,
+    }
+}
+
 diesel::table! {
     signers (pk) {
         pk -> Bytea,
@@ -27,6 +138,37 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    task_last_run (task_key) {
+        task_key -> Text,
+        last_run -> Timestamp,
+    }
+}
+
+diesel::table! {
+    task_queue (task_id) {
+        task_id -> Int8,
+        task -> Bytea,
+        attempts -> Int4,
+        run_after -> Timestamp,
+        leased_until -> Nullable<Timestamp>,
+        created -> Timestamp,
+        task_key -> Text,
+        priority -> Int2,
+        origin -> Text,
+        seed_fid -> Int8,
+        depth -> Int4,
+    }
+}
+
+diesel::table! {
+    timeline_pull_authors (fid) {
+        fid -> Int8,
+        followers -> Int8,
+        updated -> Timestamp,
+    }
+}
+
 diesel::table! {
     users (fid) {
         fid -> Int8,
@@ -38,12 +180,30 @@ diesel::table! {
     }
 }
 
+diesel::joinable!(casts -> users (fid));
+diesel::joinable!(channel_follows -> channels (channel_id));
+diesel::joinable!(channel_follows -> users (fid));
 diesel::joinable!(notifications -> users (fid));
+diesel::joinable!(reactions -> users (fid));
 diesel::joinable!(signers -> users (fid));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    cast_tombstones,
+    casts,
+    channel_follows,
+    channels,
+    dead_tasks,
+    feed_backfills,
+    home_timeline,
+    link_removals,
     links,
     notifications,
+    reaction_counts,
+    reaction_removals,
+    reactions,
     signers,
+    task_last_run,
+    task_queue,
+    timeline_pull_authors,
     users,
 );
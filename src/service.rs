@@ -1,23 +1,26 @@
-use crossbeam::channel::Sender;
 use diesel::PgConnection;
 use diesel::r2d2::ConnectionManager;
+use dotenvy::var;
 use dotenvy_macro::dotenv;
 use fatline_rs::HubService;
 use r2d2_postgres::r2d2::Pool;
 use tokio::sync::Mutex;
 
-use crate::worker::Task;
+use crate::crawl::{CrawlLimiter, CrawlPolicy};
+use crate::queue::TaskQueue;
 
 pub struct ServiceState {
     pub(crate) hub_client: Mutex<HubService>,
     pub(crate) db_pool: DbPool,
-    pub(crate) work_sender: Sender<Task>,
+    pub(crate) task_queue: TaskQueue,
+    pub(crate) crawler: CrawlLimiter,
 }
 
 pub type DbPool = Pool<ConnectionManager<PgConnection>>;
 
 const DB_URL:&'static str = dotenv!("DATABASE_URL");
 const HUB_URL: &'static str = dotenv!("SERVER_URL");
+const DEFAULT_QUEUE_CAPACITY: i64 = 100_000;
 
 impl ServiceState {
 
@@ -32,14 +35,18 @@ impl ServiceState {
             .build(pg_connection).expect("Couldn't create pg pool")
     }
 
-    pub async fn new(sender: Sender<Task>) -> Self {
+    pub async fn new() -> Self {
         let client = Self::hub_client().await;
         let pool = Self::db_pool(16).await;
+        let queue_capacity = var("TASK_QUEUE_CAPACITY").ok()
+            .and_then(|capacity| capacity.parse().ok())
+            .unwrap_or(DEFAULT_QUEUE_CAPACITY);
 
         Self {
             hub_client: Mutex::new(client),
+            task_queue: TaskQueue::new(pool.clone(), queue_capacity),
             db_pool: pool,
-            work_sender: sender
+            crawler: CrawlLimiter::new(CrawlPolicy::from_env()),
         }
     }
 }
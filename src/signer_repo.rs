@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+
 use axum::async_trait;
 use diesel::dsl::insert_into;
 use diesel::prelude::*;
@@ -6,15 +8,18 @@ use eyre::bail;
 use tracing::error;
 use crate::error::ServerError;
 use crate::schema::signers::dsl::signers;
-use crate::schema::signers::pk;
+use crate::schema::signers::{active, fid as s_fid, pk};
 use crate::schema::users::dsl::users;
 use crate::service::ServiceState;
+use crate::timeline_repo::backfill_home_timeline;
 use crate::user_models::{Signer, User};
 
 #[async_trait]
 pub trait SignerRepository {
     async fn get_signer(&self, pk_q: Vec<u8>) -> eyre::Result<Option<Signer>>;
     async fn insert_signer(&self, signer: Signer) -> eyre::Result<Signer>;
+    /// which of `fids` have an active signer on this server
+    async fn signer_fids(&self, fids: Vec<u64>) -> eyre::Result<HashSet<u64>>;
 }
 
 #[async_trait]
@@ -31,16 +36,31 @@ impl SignerRepository for ServiceState {
 
     async fn insert_signer(&self, signer: Signer) -> eyre::Result<Signer> {
         let mut db = self.db_pool.get()?;
-        let insert_result = db.transaction(|db| {
+        let insert_result = db.transaction::<_, Error, _>(|db| {
             // ensure signer's fid is pre-loaded into user table if not already, do nothing on conflict
             insert_into(users).values(User::empty(signer.fid)).on_conflict_do_nothing().execute(db)?;
-            insert_into(signers).values(signer)
+            let stored = insert_into(signers).values(signer)
                 .returning(Signer::as_returning())
-                .get_result(db)
+                .get_result(db)?;
+            // a new user's home timeline starts out with what their follows already cast
+            if stored.active {
+                backfill_home_timeline(db, &[stored.fid], None)?;
+            }
+            Ok(stored)
         }).map_err(|e| {
             error!("Error inserting into db: {e}");
             ServerError::DbError
         })?;
         Ok(insert_result)
     }
+
+    async fn signer_fids(&self, fids: Vec<u64>) -> eyre::Result<HashSet<u64>> {
+        let mut db = self.db_pool.get()?;
+        let fids: Vec<i64> = fids.into_iter().map(|fid| fid as i64).collect();
+        let found = signers.select(s_fid)
+            .filter(s_fid.eq_any(fids).and(active.eq(true)))
+            .distinct()
+            .load::<i64>(&mut db)?;
+        Ok(found.into_iter().map(|fid| fid as u64).collect())
+    }
 }
@@ -0,0 +1,187 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::queue::QueuedTask;
+use crate::worker::{Origin, Priority, Task};
+
+// how many failures are kept for the admin report
+const RECENT_FAILURES: usize = 50;
+// completions are bucketed per second over this window for the throughput rate
+const THROUGHPUT_WINDOW_SECS: u64 = 60;
+
+fn unix_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct InFlightTask {
+    pub task_id: i64,
+    pub task: Task,
+    pub priority: Priority,
+    pub origin: Origin,
+    pub attempt: i32,
+    pub started: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TaskFailure {
+    pub task_id: i64,
+    pub task: Task,
+    pub attempt: i32,
+    pub error: String,
+    pub failed: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Throughput {
+    pub completed: u64,
+    pub failed: u64,
+    pub completed_last_minute: u64,
+    pub uptime_secs: u64,
+}
+
+/**
+* Live view of what the worker is running, shared with the admin routes
+*/
+pub struct WorkerStats {
+    in_flight: DashMap<i64, InFlightTask>,
+    failures: Mutex<VecDeque<TaskFailure>>,
+    completions: Mutex<VecDeque<(u64, u64)>>,
+    completed: AtomicU64,
+    failed: AtomicU64,
+    started: Instant,
+}
+
+impl WorkerStats {
+    pub fn new() -> Self {
+        WorkerStats {
+            in_flight: DashMap::new(),
+            failures: Mutex::new(VecDeque::with_capacity(RECENT_FAILURES)),
+            completions: Mutex::new(VecDeque::new()),
+            completed: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn started(&self, queued: &QueuedTask) {
+        self.in_flight.insert(queued.task_id, InFlightTask {
+            task_id: queued.task_id,
+            task: queued.task.clone(),
+            priority: queued.priority,
+            origin: queued.origin,
+            attempt: queued.attempts,
+            started: unix_secs(),
+        });
+    }
+
+    pub fn completed(&self, task_id: i64) {
+        self.in_flight.remove(&task_id);
+        self.completed.fetch_add(1, Ordering::Relaxed);
+
+        let now = unix_secs();
+        let mut completions = self.completions.lock().unwrap();
+        match completions.back_mut() {
+            Some((second, count)) if *second == now => *count += 1,
+            _ => completions.push_back((now, 1)),
+        }
+        while completions.front().is_some_and(|(second, _)| *second + THROUGHPUT_WINDOW_SECS <= now) {
+            completions.pop_front();
+        }
+    }
+
+    pub fn failed(&self, queued: &QueuedTask, error: &str) {
+        self.in_flight.remove(&queued.task_id);
+        self.failed.fetch_add(1, Ordering::Relaxed);
+
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() == RECENT_FAILURES {
+            failures.pop_front();
+        }
+        failures.push_back(TaskFailure {
+            task_id: queued.task_id,
+            task: queued.task.clone(),
+            attempt: queued.attempts,
+            error: error.to_string(),
+            failed: unix_secs(),
+        });
+    }
+
+    /// running tasks, longest running first
+    pub fn in_flight(&self) -> Vec<InFlightTask> {
+        let mut running: Vec<InFlightTask> = self.in_flight.iter().map(|entry| entry.value().clone()).collect();
+        running.sort_by_key(|task| (task.started, task.task_id));
+        running
+    }
+
+    /// most recent first
+    pub fn recent_failures(&self) -> Vec<TaskFailure> {
+        self.failures.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    pub fn throughput(&self) -> Throughput {
+        let now = unix_secs();
+        let completed_last_minute = self.completions.lock().unwrap().iter()
+            .filter(|(second, _)| second + THROUGHPUT_WINDOW_SECS > now)
+            .map(|(_, count)| count)
+            .sum();
+        Throughput {
+            completed: self.completed.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            completed_last_minute,
+            uptime_secs: self.started.elapsed().as_secs(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crawl::Hop;
+
+    fn queued(task_id: i64) -> QueuedTask {
+        QueuedTask {
+            task_id,
+            task: Task::IndexFid(task_id as u64, false),
+            attempts: 1,
+            priority: Priority::Background,
+            origin: Origin::Crawl,
+            hop: Hop { seed: 1, depth: 0 },
+        }
+    }
+
+    #[test]
+    fn finished_tasks_leave_in_flight() {
+        let stats = WorkerStats::new();
+        stats.started(&queued(1));
+        stats.started(&queued(2));
+        assert_eq!(stats.in_flight().len(), 2);
+
+        stats.completed(1);
+        stats.failed(&queued(2), "boom");
+        assert!(stats.in_flight().is_empty());
+
+        let throughput = stats.throughput();
+        assert_eq!(throughput.completed, 1);
+        assert_eq!(throughput.failed, 1);
+        assert_eq!(throughput.completed_last_minute, 1);
+    }
+
+    #[test]
+    fn recent_failures_are_capped_newest_first() {
+        let stats = WorkerStats::new();
+        for task_id in 0..(RECENT_FAILURES as i64 + 5) {
+            stats.failed(&queued(task_id), &format!("error {task_id}"));
+        }
+        let failures = stats.recent_failures();
+        assert_eq!(failures.len(), RECENT_FAILURES);
+        assert_eq!(failures[0].task_id, RECENT_FAILURES as i64 + 4);
+        assert_eq!(failures.last().unwrap().task_id, 5);
+        assert_eq!(stats.throughput().failed, RECENT_FAILURES as u64 + 5);
+    }
+}
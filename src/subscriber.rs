@@ -1,6 +1,5 @@
 use std::any::Any;
 use std::sync::Arc;
-use crossbeam::channel::Sender;
 use fatline_rs::HubService;
 use fatline_rs::proto::{HubEvent, HubEventType, Message, MessageData, MessageType, on_chain_event, OnChainEvent, OnChainEventType, SignerEventType, SubscribeRequest};
 use fatline_rs::proto::hub_event::Body;
@@ -10,7 +9,10 @@ use tokio::task::JoinHandle;
 use tracing::{debug, error, trace};
 use crate::service::ServiceState;
 use crate::user_models::Signer;
-use crate::worker::Task;
+use crate::cast_repo::{cast_event_from_message, reaction_event_from_message};
+use crate::user_repo::link_event_from_message;
+use crate::queue::TaskQueue;
+use crate::worker::{Origin, Priority, queue_task, Task, TaskEnvelope};
 
 pub struct Subscriber {
     handle: JoinHandle<()>,
@@ -32,22 +34,38 @@ pub(crate) fn signer_from_event(event: &OnChainEvent) -> Option<Signer> {
     }
 }
 
-fn handle_merge_message(message: Message, sender: &Sender<Task>) {
+fn handle_merge_message(message: Message, queue: &TaskQueue) {
 
+    let link_event = link_event_from_message(&message);
+    let cast_event = cast_event_from_message(&message);
+    let reaction_event = reaction_event_from_message(&message);
     let data = message.data.unwrap_or_default();
 
     if let Some(body) = data.body {
         match body {
-            MBody::CastAddBody(_) => {}
-            MBody::CastRemoveBody(_) => {}
-            MBody::ReactionBody(_) => {}
+            MBody::CastAddBody(_) | MBody::CastRemoveBody(_) => {
+                // stored as they arrive so followers' timelines get them without waiting on a crawl
+                if let Some(event) = cast_event {
+                    queue_task(queue, TaskEnvelope::new(Task::UpdateCast(event), Origin::Subscriber));
+                }
+            }
+            MBody::ReactionBody(_) => {
+                if let Some(event) = reaction_event {
+                    queue_task(queue, TaskEnvelope::new(Task::UpdateReaction(event), Origin::Subscriber));
+                }
+            }
             MBody::VerificationAddAddressBody(_) => {}
             MBody::VerificationRemoveBody(_) => {}
             MBody::UserDataBody(_user_data) => {
                 // process actual user_data and insert into DB here instead of queuing the index task
-                let _ = sender.send(Task::IndexFid(data.fid, true));
+                queue_task(queue, TaskEnvelope::new(Task::IndexFid(data.fid, true), Origin::Subscriber));
+            }
+            MBody::LinkBody(_) => {
+                // every link type is stored as-is, not just follows
+                if let Some(event) = link_event {
+                    queue_task(queue, TaskEnvelope::new(Task::UpdateLink(event), Origin::Subscriber));
+                }
             }
-            MBody::LinkBody(_) => {}
             MBody::UsernameProofBody(_) => {}
             MBody::FrameActionBody(_) => {}
             MBody::LinkCompactStateBody(_) => {}
@@ -55,7 +73,7 @@ fn handle_merge_message(message: Message, sender: &Sender<Task>) {
     }
 }
 
-async fn subscribe(mut hub_client: HubService, sender: Sender<Task>) {
+async fn subscribe(mut hub_client: HubService, queue: TaskQueue) {
     let subscription_response = hub_client.subscribe(SubscribeRequest::default())
         .await
         .expect("Couldn't build subscription");
@@ -68,7 +86,7 @@ async fn subscribe(mut hub_client: HubService, sender: Sender<Task>) {
             HubEventType::MergeMessage => {
                 if let Some(Body::MergeMessageBody(body)) = message.body {
                     if let Some(message) = body.message {
-                        handle_merge_message(message, &sender);
+                        handle_merge_message(message, &queue);
                     }
                 }
             }
@@ -85,7 +103,8 @@ async fn subscribe(mut hub_client: HubService, sender: Sender<Task>) {
                 if let Some(Body::MergeOnChainEventBody(body)) = message.body {
                     body.on_chain_event.map(|event| {
                         if let Some(signer) = signer_from_event(&event) {
-                            let _ = sender.send(Task::UpdateSigner(signer));
+                            // the user is likely waiting on this to sign in
+                            queue_task(&queue, TaskEnvelope::new(Task::UpdateSigner(signer), Origin::Subscriber).with_priority(Priority::Interactive));
                         }
                     });
                 };
@@ -95,9 +114,9 @@ async fn subscribe(mut hub_client: HubService, sender: Sender<Task>) {
 }
 
 impl Subscriber {
-    pub async fn new(sender: Sender<Task>) -> Self {
+    pub async fn new(queue: TaskQueue) -> Self {
         let hub_client = ServiceState::hub_client().await;
-        let handle = tokio::spawn(subscribe(hub_client, sender));
+        let handle = tokio::spawn(subscribe(hub_client, queue));
         Self {
             handle
         }
@@ -0,0 +1,61 @@
+use axum::async_trait;
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use eyre::Result;
+use fatline_rs::users::Profile;
+use serde::Serialize;
+use tokio::task::spawn_blocking;
+
+use crate::schema::links::dsl::{fid as l_fid, link_type, links, target};
+use crate::schema::users::dsl::{fid as u_fid, users};
+use crate::service::ServiceState;
+use crate::user_models::User;
+use crate::user_repo::FOLLOW_LINK_TYPE;
+use crate::views::follow_suggestions::dsl::{fid as s_fid, follow_suggestions, overlap, suggested_fid};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Suggestion {
+    #[serde(flatten)]
+    pub profile: Profile,
+    /// how many of the viewer's follows also follow this account
+    pub overlap: i64,
+}
+
+#[async_trait]
+pub trait SuggestionRepository {
+    async fn get_suggestions(&self, fid_q: u64, limit: i64) -> Result<Vec<Suggestion>>;
+    async fn refresh_suggestions(&self) -> Result<()>;
+}
+
+#[async_trait]
+impl SuggestionRepository for ServiceState {
+    async fn get_suggestions(&self, fid_q: u64, limit: i64) -> Result<Vec<Suggestion>> {
+        let mut db = self.db_pool.get()?;
+        let suggestions = users
+            .inner_join(follow_suggestions.on(suggested_fid.eq(u_fid)))
+            .filter(s_fid.eq(fid_q as i64))
+            // the view is only as fresh as its last refresh, so drop anyone followed since
+            .filter(u_fid.ne_all(
+                links.select(target).filter(l_fid.eq(fid_q as i64).and(link_type.eq(FOLLOW_LINK_TYPE)))
+            ))
+            .select((User::as_select(), overlap))
+            .order((overlap.desc(), u_fid.asc()))
+            .limit(limit)
+            .load::<(User, i64)>(&mut db)?;
+
+        Ok(suggestions.into_iter().map(|(user, count)| Suggestion {
+            profile: user.into(),
+            overlap: count,
+        }).collect())
+    }
+
+    async fn refresh_suggestions(&self) -> Result<()> {
+        let pool = self.db_pool.clone();
+        // the refresh can take minutes on a large links table, keep it off the runtime's workers
+        spawn_blocking(move || -> Result<()> {
+            let mut db = pool.get()?;
+            db.batch_execute("REFRESH MATERIALIZED VIEW CONCURRENTLY follow_suggestions")?;
+            Ok(())
+        }).await?
+    }
+}
@@ -0,0 +1,117 @@
+use serde::Serialize;
+
+use crate::cast_models::{CastView, hex_bytes};
+
+/// One step up a thread from the focal cast
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ThreadAncestor {
+    Cast(CastView),
+    /// deleted by its author, the thread carries on above it
+    Deleted {
+        fid: u64,
+        #[serde(serialize_with = "hex_bytes::serialize")]
+        hash: Vec<u8>,
+    },
+    /// not indexed yet, nothing above it is known
+    Missing {
+        fid: u64,
+        #[serde(serialize_with = "hex_bytes::serialize")]
+        hash: Vec<u8>,
+    },
+}
+
+/// A cast in a thread and the best of its own replies
+#[derive(Serialize, Debug, Clone)]
+pub struct ThreadReply {
+    #[serde(flatten)]
+    pub cast: CastView,
+    /// direct replies we have indexed, `replies` may only hold some of them
+    pub reply_count: u64,
+    pub replies: Vec<ThreadReply>,
+}
+
+impl ThreadReply {
+    fn collect_casts_mut<'a>(&'a mut self, out: &mut Vec<&'a mut CastView>) {
+        out.push(&mut self.cast);
+        for reply in self.replies.iter_mut() {
+            reply.collect_casts_mut(out);
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ThreadPage {
+    /// root first
+    pub ancestors: Vec<ThreadAncestor>,
+    pub cast: ThreadReply,
+    pub next_page: Option<String>,
+}
+
+impl ThreadPage {
+    /// every cast on the page, ancestors and replies included
+    pub fn casts_mut(&mut self) -> Vec<&mut CastView> {
+        let mut casts = self.ancestors.iter_mut().filter_map(|ancestor| match ancestor {
+            ThreadAncestor::Cast(cast) => Some(cast),
+            _ => None
+        }).collect::<Vec<_>>();
+        self.cast.collect_casts_mut(&mut casts);
+        casts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use crate::cast_models::{CastViewerContext, encode_embeds, StoredCast};
+
+    use super::*;
+
+    fn view(hash: u8) -> CastView {
+        CastView::from(StoredCast {
+            hash: vec![hash],
+            fid: 1,
+            text: String::new(),
+            parent_hash: None,
+            parent_fid: None,
+            parent_url: None,
+            embeds: encode_embeds(&[]),
+            mentions: vec![],
+            mentions_positions: vec![],
+            timestamp: UNIX_EPOCH,
+        })
+    }
+
+    fn reply(hash: u8, replies: Vec<ThreadReply>) -> ThreadReply {
+        ThreadReply { cast: view(hash), reply_count: replies.len() as u64, replies }
+    }
+
+    #[test]
+    fn page_casts_cover_ancestors_and_every_reply() {
+        let mut page = ThreadPage {
+            ancestors: vec![
+                ThreadAncestor::Cast(view(1)),
+                ThreadAncestor::Deleted { fid: 1, hash: vec![2] },
+                ThreadAncestor::Cast(view(3)),
+            ],
+            cast: reply(4, vec![reply(5, vec![reply(6, vec![])]), reply(7, vec![])]),
+            next_page: None,
+        };
+        let hashes = page.casts_mut().iter().map(|cast| cast.hash[0]).collect::<Vec<_>>();
+        assert_eq!(hashes, vec![1, 3, 4, 5, 6, 7]);
+
+        let context = CastViewerContext { liked: true, recasted: false, follows_author: true };
+        for cast in page.casts_mut() {
+            cast.set_viewer_context(context);
+        }
+        let deepest = &page.cast.replies[0].replies[0].cast;
+        assert_eq!((deepest.viewer_liked, deepest.viewer_recasted, deepest.viewer_follows_author), (Some(true), Some(false), Some(true)));
+    }
+
+    #[test]
+    fn casts_have_no_viewer_context_until_set() {
+        let cast = view(1);
+        assert_eq!((cast.viewer_liked, cast.viewer_recasted, cast.viewer_follows_author), (None, None, None));
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+
+use axum::async_trait;
+use diesel::prelude::*;
+use diesel::PgConnection;
+use diesel::sql_types::{Array, BigInt, Bytea, Int8, Nullable};
+use eyre::Result;
+
+use crate::cast_models::{CastTombstone, CastView, ReactionCounts, StoredCast};
+use crate::cast_repo::load_reaction_counts;
+use crate::pagination::Cursor;
+use crate::service::ServiceState;
+use crate::thread::{ThreadAncestor, ThreadReply};
+
+// same bound the indexer walks up to
+const MAX_THREAD_ANCESTORS: usize = 64;
+// levels of replies shown under each reply on a page
+const REPLY_PREVIEW_DEPTH: usize = 2;
+// replies shown per parent in those levels
+const REPLY_PREVIEW_LIMIT: i64 = 3;
+
+/*
+* Replies to any of $1, best first per parent: casts by accounts the viewer follows, then by reaction count,
+* then newest. sort_key packs that rank into bytes so it can be compared and used as a cursor.
+* Used for the previews under each reply, which aren't paged
+*/
+const THREAD_REPLIES_QUERY: &'static str = r#"
+SELECT * FROM (
+    SELECT c.*, r.reply_count, r.sort_key,
+           row_number() OVER (PARTITION BY c.parent_hash ORDER BY r.sort_key DESC) AS parent_rank
+    FROM casts c
+    CROSS JOIN LATERAL (
+        SELECT
+            (SELECT count(*) FROM casts rc WHERE rc.parent_hash = c.hash) AS reply_count,
+            int8send(
+                CASE WHEN EXISTS (
+                    SELECT 1 FROM links l WHERE l.fid = $2 AND l.target = c.fid AND l.link_type = 'follow'
+                ) THEN 1::int8 << 40 ELSE 0 END
+                + least(coalesce((SELECT x.likes + x.recasts FROM reaction_counts x WHERE x.hash = c.hash), 0), (1::int8 << 40) - 1)
+            ) || int8send((extract(epoch FROM c.timestamp) * 1000000)::int8) || c.hash AS sort_key
+    ) r
+    WHERE c.parent_hash = ANY($1)
+) replies
+WHERE parent_rank <= $3
+ORDER BY sort_key DESC
+"#;
+
+/*
+* A page of direct replies to $1, ranked the same way as THREAD_REPLIES_QUERY and paged on sort_key,
+* so the whole thread is in rank order across pages. A reply whose reactions change between pages can move
+*/
+const THREAD_PAGE_QUERY: &'static str = r#"
+SELECT * FROM (
+    SELECT c.*, r.reply_count, r.sort_key
+    FROM casts c
+    CROSS JOIN LATERAL (
+        SELECT
+            (SELECT count(*) FROM casts rc WHERE rc.parent_hash = c.hash) AS reply_count,
+            int8send(
+                CASE WHEN EXISTS (
+                    SELECT 1 FROM links l WHERE l.fid = $2 AND l.target = c.fid AND l.link_type = 'follow'
+                ) THEN 1::int8 << 40 ELSE 0 END
+                + least(coalesce((SELECT x.likes + x.recasts FROM reaction_counts x WHERE x.hash = c.hash), 0), (1::int8 << 40) - 1)
+            ) || int8send((extract(epoch FROM c.timestamp) * 1000000)::int8) || c.hash AS sort_key
+    ) r
+    WHERE c.parent_hash = $1
+) replies
+WHERE $4::bytea IS NULL OR sort_key < $4
+ORDER BY sort_key DESC
+LIMIT $3
+"#;
+
+#[derive(QueryableByName, Debug)]
+struct ReplyRow {
+    #[diesel(embed)]
+    cast: StoredCast,
+    #[diesel(sql_type = BigInt)]
+    reply_count: i64,
+    #[diesel(sql_type = Bytea)]
+    sort_key: Vec<u8>,
+}
+
+fn load_replies(db: &mut PgConnection, viewer: u64, parents: &[Vec<u8>], per_parent: i64) -> QueryResult<Vec<ReplyRow>> {
+    diesel::sql_query(THREAD_REPLIES_QUERY)
+        .bind::<Array<Bytea>, _>(parents)
+        .bind::<Int8, _>(viewer as i64)
+        .bind::<Int8, _>(per_parent)
+        .load(db)
+}
+
+fn load_reply_page(db: &mut PgConnection, viewer: u64, parent: &[u8], limit: i64, cursor: Option<Cursor>) -> QueryResult<Vec<ReplyRow>> {
+    diesel::sql_query(THREAD_PAGE_QUERY)
+        .bind::<Bytea, _>(parent)
+        .bind::<Int8, _>(viewer as i64)
+        .bind::<Int8, _>(limit)
+        .bind::<Nullable<Bytea>, _>(cursor.map(|c| c.key))
+        .load(db)
+}
+
+// built from the deepest level up, rows stay in rank order under each parent
+fn assemble_replies(levels: Vec<Vec<ReplyRow>>, root: &[u8], counts: &HashMap<Vec<u8>, ReactionCounts>) -> Vec<ThreadReply> {
+    let mut children = HashMap::<Vec<u8>, Vec<ThreadReply>>::new();
+    for level in levels.into_iter().rev() {
+        let mut parents = HashMap::<Vec<u8>, Vec<ThreadReply>>::new();
+        for row in level {
+            let replies = children.remove(&row.cast.hash).unwrap_or_default();
+            let parent = row.cast.parent_hash.clone().unwrap_or_default();
+            parents.entry(parent).or_default().push(ThreadReply {
+                cast: CastView::from(row.cast).with_reactions(counts),
+                reply_count: row.reply_count as u64,
+                replies,
+            });
+        }
+        children = parents;
+    }
+    children.remove(root).unwrap_or_default()
+}
+
+#[async_trait]
+pub trait ThreadRepository {
+    /// the casts above `cast`, root first, ending at the first one we don't have
+    async fn get_thread_ancestors(&self, cast: &StoredCast) -> Result<Vec<ThreadAncestor>>;
+    /// direct replies to hash_q ranked for viewer and paged in that order, each with a preview of the replies below it
+    async fn get_thread_replies(&self, viewer: u64, hash_q: Vec<u8>, limit: i64, cursor: Option<Cursor>) -> Result<(Vec<ThreadReply>, Option<Cursor>)>;
+    async fn count_replies(&self, hash_q: Vec<u8>) -> Result<u64>;
+}
+
+#[async_trait]
+impl ThreadRepository for ServiceState {
+    async fn get_thread_ancestors(&self, cast: &StoredCast) -> Result<Vec<ThreadAncestor>> {
+        use crate::schema::casts::dsl::{casts, hash};
+        use crate::schema::cast_tombstones::dsl::{cast_tombstones, hash as t_hash};
+
+        let mut db = self.db_pool.get()?;
+        let mut ancestors = Vec::new();
+        let mut parent = cast.parent_fid.zip(cast.parent_hash.clone());
+        while let Some((parent_fid, parent_hash)) = parent.take() {
+            if ancestors.len() >= MAX_THREAD_ANCESTORS {
+                break;
+            }
+            let stored = casts.select(StoredCast::as_select())
+                .filter(hash.eq(&parent_hash))
+                .get_result(&mut db)
+                .optional()?;
+            if let Some(stored) = stored {
+                parent = stored.parent_fid.zip(stored.parent_hash.clone());
+                ancestors.push(ThreadAncestor::Cast(stored.into()));
+                continue;
+            }
+
+            let tombstone = cast_tombstones.select(CastTombstone::as_select())
+                .filter(t_hash.eq(&parent_hash))
+                .get_result(&mut db)
+                .optional()?;
+            match tombstone {
+                Some(tombstone) => {
+                    parent = tombstone.parent_fid.zip(tombstone.parent_hash);
+                    ancestors.push(ThreadAncestor::Deleted {
+                        fid: tombstone.fid as u64,
+                        hash: tombstone.hash,
+                    });
+                }
+                None => ancestors.push(ThreadAncestor::Missing {
+                    fid: parent_fid as u64,
+                    hash: parent_hash,
+                })
+            }
+        }
+        ancestors.reverse();
+
+        let hashes = ancestors.iter().filter_map(|ancestor| match ancestor {
+            ThreadAncestor::Cast(cast) => Some(cast.hash.clone()),
+            _ => None
+        }).collect::<Vec<_>>();
+        let counts = load_reaction_counts(&mut db, &hashes)?;
+        Ok(ancestors.into_iter().map(|ancestor| match ancestor {
+            ThreadAncestor::Cast(cast) => ThreadAncestor::Cast(cast.with_reactions(&counts)),
+            other => other
+        }).collect())
+    }
+
+    async fn get_thread_replies(&self, viewer: u64, hash_q: Vec<u8>, limit: i64, cursor: Option<Cursor>) -> Result<(Vec<ThreadReply>, Option<Cursor>)> {
+        let mut db = self.db_pool.get()?;
+        let mut page = load_reply_page(&mut db, viewer, &hash_q, limit + 1, cursor)?;
+
+        // the extra row only tells us there's another page
+        let next = if page.len() as i64 > limit {
+            page.truncate(limit.max(0) as usize);
+            page.last().map(|last| Cursor::new(last.cast.timestamp, last.sort_key.clone()))
+        } else {
+            None
+        };
+
+        let mut levels = vec![page];
+        for _ in 0..REPLY_PREVIEW_DEPTH {
+            let parents = levels.last()
+                .map(|level| level.iter().map(|row| row.cast.hash.clone()).collect::<Vec<_>>())
+                .unwrap_or_default();
+            if parents.is_empty() {
+                break;
+            }
+            levels.push(load_replies(&mut db, viewer, &parents, REPLY_PREVIEW_LIMIT)?);
+        }
+
+        let hashes = levels.iter().flatten().map(|row| row.cast.hash.clone()).collect::<Vec<_>>();
+        let counts = load_reaction_counts(&mut db, &hashes)?;
+        Ok((assemble_replies(levels, &hash_q, &counts), next))
+    }
+
+    async fn count_replies(&self, hash_q: Vec<u8>) -> Result<u64> {
+        use crate::schema::casts::dsl::{casts, parent_hash};
+
+        let mut db = self.db_pool.get()?;
+        let count = casts.filter(parent_hash.eq(hash_q))
+            .count()
+            .get_result::<i64>(&mut db)?;
+        Ok(count as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::cast_models::encode_embeds;
+
+    use super::*;
+
+    fn reply(hash: u8, parent: u8, reply_count: i64) -> ReplyRow {
+        ReplyRow {
+            cast: StoredCast {
+                hash: vec![hash],
+                fid: 1,
+                text: String::new(),
+                parent_hash: Some(vec![parent]),
+                parent_fid: Some(1),
+                parent_url: None,
+                embeds: encode_embeds(&[]),
+                mentions: vec![],
+                mentions_positions: vec![],
+                timestamp: UNIX_EPOCH + Duration::from_secs(hash as u64),
+            },
+            reply_count,
+            sort_key: vec![],
+        }
+    }
+
+    fn shape(replies: &[ThreadReply]) -> Vec<(u8, u64, Vec<u8>)> {
+        replies.iter()
+            .map(|reply| (reply.cast.hash[0], reply.reply_count, reply.replies.iter().map(|r| r.cast.hash[0]).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn replies_nest_under_their_parents_in_rank_order() {
+        let levels = vec![
+            vec![reply(2, 1, 2), reply(3, 1, 1)],
+            vec![reply(5, 2, 0), reply(4, 2, 1), reply(6, 3, 0)],
+            vec![reply(7, 4, 0)],
+        ];
+        let tree = assemble_replies(levels, &[1], &HashMap::new());
+        assert_eq!(shape(&tree), vec![(2, 2, vec![5, 4]), (3, 1, vec![6])]);
+        assert_eq!(shape(&tree[0].replies), vec![(5, 0, vec![]), (4, 1, vec![7])]);
+    }
+
+    #[test]
+    fn replies_to_other_casts_are_left_out() {
+        let tree = assemble_replies(vec![vec![reply(2, 1, 0), reply(9, 8, 0)]], &[1], &HashMap::new());
+        assert_eq!(shape(&tree), vec![(2, 0, vec![])]);
+        assert!(assemble_replies(vec![], &[1], &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn replies_carry_their_reaction_counts() {
+        let counts = HashMap::from([(vec![3], ReactionCounts { likes: 4, recasts: 1 })]);
+        let tree = assemble_replies(vec![vec![reply(2, 1, 1)], vec![reply(3, 2, 0)]], &[1], &counts);
+        assert_eq!(tree[0].cast.reactions, ReactionCounts::default());
+        assert_eq!(tree[0].replies[0].cast.reactions, ReactionCounts { likes: 4, recasts: 1 });
+    }
+}
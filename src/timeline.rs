@@ -1,6 +1,32 @@
-use fatline_rs::posts::Cast;
+use serde::Serialize;
 
+use crate::cast_models::CastView;
+
+/// A cast in a timeline, along with who recast it if that's why it's there
+#[derive(Serialize, Debug, Clone)]
+pub struct TimelineCast {
+    #[serde(flatten)]
+    pub cast: CastView,
+    pub recasted_by: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
 pub(crate) struct TimelinePage {
-    casts: Vec<Cast>,
-    next_page: Option<Vec<u8>>,
+    pub casts: Vec<TimelineCast>,
+    pub next_page: Option<String>,
+}
+
+impl TimelinePage {
+    pub fn casts_mut(&mut self) -> Vec<&mut CastView> {
+        self.casts.iter_mut().map(|timeline_cast| &mut timeline_cast.cast).collect()
+    }
+}
+
+/// What besides top level casts a timeline includes
+#[derive(Debug, Copy, Clone, Default)]
+pub struct TimelineFilter {
+    pub replies: bool,
+    pub recasts: bool,
+    /// casts in followed channels by accounts the viewer doesn't follow
+    pub channels: bool,
 }
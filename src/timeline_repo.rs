@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use axum::async_trait;
+use diesel::prelude::*;
+use diesel::PgConnection;
+use diesel::sql_types::{Array, BigInt, Bool, Bytea, Int8, Nullable, Timestamp};
+use eyre::Result;
+
+use crate::cast_models::{CastView, Reaction, ReactionKind, StoredCast};
+use crate::cast_repo::load_reaction_counts;
+use crate::pagination::Cursor;
+use crate::service::ServiceState;
+use crate::timeline::{TimelineCast, TimelineFilter};
+
+// authors with at least this many followers aren't fanned out, their casts are pulled on read instead
+const FANOUT_FOLLOWER_LIMIT: i64 = 10_000;
+// timelines are trimmed back to this many entries
+const MAX_TIMELINE_LEN: i64 = 800;
+// newest casts copied in from each account when a timeline is backfilled
+const BACKFILL_PER_AUTHOR: i64 = 50;
+
+/*
+* materialized entries, plus whatever pull authors the viewer follows cast or recast
+* and what's posted to channels the viewer follows, newest first
+*/
+const HOME_TIMELINE_QUERY: &'static str = r#"
+SELECT * FROM (
+    SELECT c.*, h.recasted_by, h.timestamp AS sort_time, h.sort_key
+    FROM home_timeline h
+    JOIN casts c ON c.hash = h.cast_hash
+    WHERE h.fid = $1
+      AND ($2 OR NOT h.is_reply)
+      AND ($3 OR h.recasted_by IS NULL)
+      -- fanned out before the author became a pull author, the pull below covers these
+      AND NOT EXISTS (SELECT 1 FROM timeline_pull_authors p WHERE p.fid = coalesce(h.recasted_by, h.author_fid))
+    UNION ALL
+    SELECT c.*, NULL::int8, c.timestamp, c.hash
+    FROM timeline_pull_authors p
+    JOIN links l ON l.target = p.fid
+    JOIN casts c ON c.fid = p.fid
+    WHERE l.fid = $1 AND l.link_type = 'follow'
+      AND ($2 OR c.parent_hash IS NULL)
+    UNION ALL
+    SELECT c.*, r.fid, r.timestamp, c.hash || int8send(r.fid)
+    FROM timeline_pull_authors p
+    JOIN links l ON l.target = p.fid
+    JOIN reactions r ON r.fid = p.fid AND r.reaction_type = 2
+    JOIN casts c ON c.hash = r.target_hash
+    WHERE $3 AND l.fid = $1 AND l.link_type = 'follow'
+    UNION ALL
+    SELECT c.*, NULL::int8, c.timestamp, c.hash
+    FROM channel_follows f
+    JOIN channels ch ON ch.id = f.channel_id
+    JOIN casts c ON c.parent_url = ch.url
+    WHERE $7 AND f.fid = $1
+      -- casts by followed accounts are already in the timeline
+      AND NOT EXISTS (SELECT 1 FROM links l WHERE l.fid = $1 AND l.target = c.fid AND l.link_type = 'follow')
+) timeline
+WHERE $4::timestamp IS NULL OR (sort_time, sort_key) < ($4, $5)
+ORDER BY sort_time DESC, sort_key DESC
+LIMIT $6
+"#;
+
+// the count is bounded so it stays cheap for the authors it's there to catch
+const FOLLOWER_COUNT_QUERY: &'static str = r#"
+SELECT count(*) AS followers FROM (
+    SELECT 1 FROM links WHERE target = $1 AND link_type = 'follow' LIMIT $2
+) capped
+"#;
+
+// only followers with a signer here can ever read a home timeline
+const FAN_OUT_QUERY: &'static str = r#"
+INSERT INTO home_timeline (fid, sort_key, cast_hash, author_fid, recasted_by, is_reply, timestamp)
+SELECT l.fid, $1, $2, $3, $4, $5, $6
+FROM links l
+WHERE l.target = $7 AND l.link_type = 'follow'
+  AND EXISTS (SELECT 1 FROM signers s WHERE s.fid = l.fid AND s.active)
+ON CONFLICT DO NOTHING
+"#;
+
+// copies the newest casts and recasts of the accounts each of $1 follows (or just those in $3) into their timelines
+const BACKFILL_QUERY: &'static str = r#"
+INSERT INTO home_timeline (fid, sort_key, cast_hash, author_fid, recasted_by, is_reply, timestamp)
+SELECT l.fid, c.hash, c.hash, c.fid, NULL, c.parent_hash IS NOT NULL, c.timestamp
+FROM links l
+JOIN LATERAL (
+    SELECT * FROM casts WHERE casts.fid = l.target ORDER BY timestamp DESC LIMIT $4
+) c ON true
+WHERE l.fid = ANY($1) AND l.link_type = 'follow'
+  AND ($2 OR l.target = ANY($3))
+  AND NOT EXISTS (SELECT 1 FROM timeline_pull_authors p WHERE p.fid = l.target)
+  AND EXISTS (SELECT 1 FROM signers s WHERE s.fid = l.fid AND s.active)
+UNION ALL
+SELECT l.fid, r.target_hash || int8send(r.fid), r.target_hash, r.target_fid, r.fid, false, r.timestamp
+FROM links l
+JOIN LATERAL (
+    SELECT * FROM reactions
+    WHERE reactions.fid = l.target AND reactions.reaction_type = 2
+    ORDER BY timestamp DESC LIMIT $4
+) r ON true
+WHERE l.fid = ANY($1) AND l.link_type = 'follow'
+  AND ($2 OR l.target = ANY($3))
+  AND NOT EXISTS (SELECT 1 FROM timeline_pull_authors p WHERE p.fid = l.target)
+  AND EXISTS (SELECT 1 FROM signers s WHERE s.fid = l.fid AND s.active)
+ON CONFLICT DO NOTHING
+"#;
+
+// $1 and $2 pair up each fid with an account they stopped following
+const UNFOLLOW_QUERY: &'static str = r#"
+DELETE FROM home_timeline h
+USING unnest($1::int8[], $2::int8[]) AS u(fid, target)
+WHERE h.fid = u.fid AND coalesce(h.recasted_by, h.author_fid) = u.target
+"#;
+
+/*
+* per fid, finds the first entry past $1 on home_timeline_fid_timestamp_idx and deletes it and everything older.
+* Timelines only ever get filled for fids with a signer here
+*/
+const TRIM_QUERY: &'static str = r#"
+DELETE FROM home_timeline h
+USING (
+    SELECT s.fid, cutoff.timestamp, cutoff.sort_key
+    FROM (SELECT DISTINCT fid FROM signers) s
+    CROSS JOIN LATERAL (
+        SELECT timestamp, sort_key FROM home_timeline t
+        WHERE t.fid = s.fid
+        ORDER BY timestamp DESC, sort_key DESC
+        OFFSET $1 LIMIT 1
+    ) cutoff
+) overflow
+WHERE h.fid = overflow.fid AND (h.timestamp, h.sort_key) <= (overflow.timestamp, overflow.sort_key)
+"#;
+
+const MARK_PULL_AUTHOR_QUERY: &'static str = r#"
+INSERT INTO timeline_pull_authors (fid, followers) VALUES ($1, $2)
+ON CONFLICT (fid) DO UPDATE SET followers = excluded.followers, updated = now()
+"#;
+
+#[derive(QueryableByName, Debug)]
+struct TimelineRow {
+    #[diesel(embed)]
+    cast: StoredCast,
+    #[diesel(sql_type = Nullable<Int8>)]
+    recasted_by: Option<i64>,
+    #[diesel(sql_type = Timestamp)]
+    sort_time: SystemTime,
+    #[diesel(sql_type = Bytea)]
+    sort_key: Vec<u8>,
+}
+
+#[derive(QueryableByName, Debug)]
+struct FollowerCount {
+    #[diesel(sql_type = BigInt)]
+    followers: i64,
+}
+
+fn recast_key(hash: &[u8], recaster: i64) -> Vec<u8> {
+    let mut key = hash.to_vec();
+    key.extend_from_slice(&recaster.to_be_bytes());
+    key
+}
+
+/**
+* whether fid's casts are pulled on read rather than fanned out.
+* Authors stay pull authors once marked, so their older entries never need re-fanning
+*/
+fn is_pull_author(db: &mut PgConnection, author: i64) -> QueryResult<bool> {
+    use crate::schema::timeline_pull_authors::dsl::{fid, timeline_pull_authors};
+
+    let marked = timeline_pull_authors.select(fid).filter(fid.eq(author)).first::<i64>(db).optional()?;
+    if marked.is_some() {
+        return Ok(true);
+    }
+    let count = diesel::sql_query(FOLLOWER_COUNT_QUERY)
+        .bind::<BigInt, _>(author)
+        .bind::<BigInt, _>(FANOUT_FOLLOWER_LIMIT)
+        .get_result::<FollowerCount>(db)?;
+    if count.followers < FANOUT_FOLLOWER_LIMIT {
+        return Ok(false);
+    }
+    diesel::sql_query(MARK_PULL_AUTHOR_QUERY)
+        .bind::<BigInt, _>(author)
+        .bind::<BigInt, _>(count.followers)
+        .execute(db)?;
+    Ok(true)
+}
+
+fn fan_out(db: &mut PgConnection, sort_key: &[u8], cast: (&[u8], i64), recasted_by: Option<i64>, is_reply: bool, timestamp: SystemTime) -> QueryResult<usize> {
+    let (cast_hash, author) = cast;
+    diesel::sql_query(FAN_OUT_QUERY)
+        .bind::<Bytea, _>(sort_key)
+        .bind::<Bytea, _>(cast_hash)
+        .bind::<BigInt, _>(author)
+        .bind::<Nullable<BigInt>, _>(recasted_by)
+        .bind::<Bool, _>(is_reply)
+        .bind::<Timestamp, _>(timestamp)
+        .bind::<BigInt, _>(recasted_by.unwrap_or(author))
+        .execute(db)
+}
+
+/// copies newly stored casts into their authors' followers' timelines
+pub(crate) fn fan_out_casts(db: &mut PgConnection, new_casts: &[StoredCast]) -> QueryResult<()> {
+    let mut pull_authors = HashMap::new();
+    for cast in new_casts {
+        let pull = match pull_authors.get(&cast.fid) {
+            Some(pull) => *pull,
+            None => {
+                let pull = is_pull_author(db, cast.fid)?;
+                pull_authors.insert(cast.fid, pull);
+                pull
+            }
+        };
+        if !pull {
+            fan_out(db, &cast.hash, (&cast.hash, cast.fid), None, cast.is_reply(), cast.timestamp)?;
+        }
+    }
+    Ok(())
+}
+
+/// copies newly stored recasts into their recasters' followers' timelines
+pub(crate) fn fan_out_recasts(db: &mut PgConnection, new_reactions: &[Reaction]) -> QueryResult<()> {
+    let mut pull_authors = HashMap::new();
+    for recast in new_reactions.iter().filter(|reaction| reaction.reaction_type == ReactionKind::Recast.code()) {
+        let pull = match pull_authors.get(&recast.fid) {
+            Some(pull) => *pull,
+            None => {
+                let pull = is_pull_author(db, recast.fid)?;
+                pull_authors.insert(recast.fid, pull);
+                pull
+            }
+        };
+        if !pull {
+            let key = recast_key(&recast.target_hash, recast.fid);
+            fan_out(db, &key, (&recast.target_hash, recast.target_fid), Some(recast.fid), false, recast.timestamp)?;
+        }
+    }
+    Ok(())
+}
+
+/// fills the timelines of `fids` from everyone they follow, or just from `targets`
+pub(crate) fn backfill_home_timeline(db: &mut PgConnection, fids: &[i64], targets: Option<&[i64]>) -> QueryResult<usize> {
+    if fids.is_empty() || targets.is_some_and(|targets| targets.is_empty()) {
+        return Ok(0);
+    }
+    diesel::sql_query(BACKFILL_QUERY)
+        .bind::<Array<Int8>, _>(fids)
+        .bind::<Bool, _>(targets.is_none())
+        .bind::<Array<Int8>, _>(targets.unwrap_or_default())
+        .bind::<BigInt, _>(BACKFILL_PER_AUTHOR)
+        .execute(db)
+}
+
+/// takes what each (fid, target) follow brought into fid's timeline back out
+pub(crate) fn remove_follows_from_timelines(db: &mut PgConnection, follows: &[(i64, i64)]) -> QueryResult<usize> {
+    if follows.is_empty() {
+        return Ok(0);
+    }
+    let (fids, targets): (Vec<i64>, Vec<i64>) = follows.iter().copied().unzip();
+    diesel::sql_query(UNFOLLOW_QUERY)
+        .bind::<Array<Int8>, _>(fids)
+        .bind::<Array<Int8>, _>(targets)
+        .execute(db)
+}
+
+/// keeps fid's timeline in step with them following or unfollowing target
+pub(crate) fn follow_changed(db: &mut PgConnection, fid: i64, target: i64, following: bool) -> QueryResult<usize> {
+    if following {
+        backfill_home_timeline(db, &[fid], Some(&[target]))
+    } else {
+        remove_follows_from_timelines(db, &[(fid, target)])
+    }
+}
+
+/// drops a deleted cast, and recasts of it, from every timeline
+pub(crate) fn remove_from_timelines(db: &mut PgConnection, hash: &[u8]) -> QueryResult<usize> {
+    use crate::schema::home_timeline::dsl::{cast_hash, home_timeline};
+
+    diesel::delete(home_timeline.filter(cast_hash.eq(hash))).execute(db)
+}
+
+/// drops an undone recast from the recaster's followers' timelines
+pub(crate) fn remove_recast_from_timelines(db: &mut PgConnection, recaster: i64, hash: &[u8]) -> QueryResult<usize> {
+    use crate::schema::home_timeline::dsl::{cast_hash, home_timeline, recasted_by};
+
+    diesel::delete(home_timeline.filter(cast_hash.eq(hash).and(recasted_by.eq(recaster)))).execute(db)
+}
+
+#[async_trait]
+pub trait TimelineRepository {
+    async fn get_home_timeline(&self, viewer: u64, filter: TimelineFilter, limit: i64, cursor: Option<Cursor>) -> Result<(Vec<TimelineCast>, Option<Cursor>)>;
+    /// cuts every timeline back to its newest MAX_TIMELINE_LEN entries
+    async fn trim_home_timelines(&self) -> Result<usize>;
+}
+
+#[async_trait]
+impl TimelineRepository for ServiceState {
+    async fn get_home_timeline(&self, viewer: u64, filter: TimelineFilter, limit: i64, cursor: Option<Cursor>) -> Result<(Vec<TimelineCast>, Option<Cursor>)> {
+        let mut db = self.db_pool.get()?;
+        let mut rows = diesel::sql_query(HOME_TIMELINE_QUERY)
+            .bind::<Int8, _>(viewer as i64)
+            .bind::<Bool, _>(filter.replies)
+            .bind::<Bool, _>(filter.recasts)
+            .bind::<Nullable<Timestamp>, _>(cursor.as_ref().map(Cursor::time))
+            .bind::<Bytea, _>(cursor.map(|c| c.key).unwrap_or_default())
+            .bind::<Int8, _>(limit + 1)
+            .bind::<Bool, _>(filter.channels)
+            .load::<TimelineRow>(&mut db)?;
+
+        // the extra row only tells us there's another page
+        let next = if rows.len() as i64 > limit {
+            rows.truncate(limit as usize);
+            rows.last().map(|row| Cursor::new(row.sort_time, row.sort_key.clone()))
+        } else {
+            None
+        };
+
+        let hashes = rows.iter().map(|row| row.cast.hash.clone()).collect::<Vec<_>>();
+        let counts = load_reaction_counts(&mut db, &hashes)?;
+
+        Ok((rows.into_iter().map(|row| TimelineCast {
+            cast: CastView::from(row.cast).with_reactions(&counts),
+            recasted_by: row.recasted_by.map(|fid| fid as u64),
+        }).collect(), next))
+    }
+
+    async fn trim_home_timelines(&self) -> Result<usize> {
+        let mut db = self.db_pool.get()?;
+        Ok(diesel::sql_query(TRIM_QUERY)
+            .bind::<BigInt, _>(MAX_TIMELINE_LEN)
+            .execute(&mut db)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn recast_cursor_round_trips() {
+        let key = recast_key(&[0xab; 20], 9);
+        assert_eq!(key.len(), 28);
+        assert_eq!(&key[20..], &9i64.to_be_bytes());
+        let cursor = Cursor::new(UNIX_EPOCH + Duration::from_micros(1_700_000_000_000_001), key.clone());
+        let decoded = Cursor::decode(&cursor.encode()).unwrap();
+        assert_eq!(decoded.time(), cursor.time());
+        assert_eq!(decoded.key, key);
+    }
+}
@@ -1,9 +1,10 @@
-use std::time::{SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use diesel::prelude::*;
+use fatline_rs::users::Profile;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Queryable, Selectable, Insertable, AsChangeset, Debug, Clone)]
+#[derive(Queryable, QueryableByName, Selectable, Insertable, AsChangeset, Debug, Clone)]
 #[diesel(table_name=crate::schema::users)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct User {
@@ -28,7 +29,12 @@ impl User {
     }
 }
 
-#[derive(Queryable, Selectable, Insertable, AsChangeset, Debug, Eq, PartialEq, Hash, Clone)]
+pub fn fc_time(fc_timestamp: u32) -> SystemTime {
+    let timestamp = fatline_rs::utils::fc_timestamp_to_unix(fc_timestamp).unwrap_or_default();
+    UNIX_EPOCH + Duration::from_secs(timestamp as u64)
+}
+
+#[derive(Serialize, Deserialize, Queryable, Selectable, Insertable, AsChangeset, Debug, Eq, PartialEq, Hash, Clone)]
 #[diesel(table_name=crate::schema::signers)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Signer {
@@ -45,6 +51,28 @@ pub struct Link {
     pub fid: i64,
     pub target: i64,
     pub timestamp: SystemTime,
+    pub link_type: String,
+}
+
+/// A link add or remove for any link type, as seen in hub messages
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Clone)]
+pub struct LinkEvent {
+    pub fid: u64,
+    pub target: u64,
+    pub link_type: String,
+    pub timestamp: u32,
+    pub removed: bool,
+}
+
+impl LinkEvent {
+    pub fn to_link(&self) -> Link {
+        Link {
+            fid: self.fid as i64,
+            target: self.target as i64,
+            timestamp: fc_time(self.timestamp),
+            link_type: self.link_type.clone(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Queryable, Selectable, Insertable, Associations, Debug, Clone)]
@@ -59,3 +87,34 @@ pub struct Notification {
     pub created: SystemTime,
     pub viewed: bool
 }
+
+/// Relationship of a profile to the authenticated viewer
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone)]
+pub struct ViewerContext {
+    pub viewer_follows: bool,
+    pub follows_viewer: bool,
+    pub mutual_followers: i64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ViewerProfile {
+    #[serde(flatten)]
+    pub profile: Profile,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub viewer_follows: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub follows_viewer: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mutual_followers: Option<i64>,
+}
+
+impl ViewerProfile {
+    pub fn new(profile: Profile, context: Option<ViewerContext>) -> Self {
+        ViewerProfile {
+            profile,
+            viewer_follows: context.map(|c| c.viewer_follows),
+            follows_viewer: context.map(|c| c.follows_viewer),
+            mutual_followers: context.map(|c| c.mutual_followers),
+        }
+    }
+}
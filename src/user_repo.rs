@@ -1,31 +1,44 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use axum::async_trait;
 use chrono::{DateTime, NaiveDateTime};
-use diesel::{BoolExpressionMethods, Connection, EqAll, ExpressionMethods, Insertable, JoinOnDsl, QueryDsl, QuerySource, RunQueryDsl, Selectable, SelectableHelper, Table};
+use diesel::{alias, BoolExpressionMethods, PgConnection, QueryResult, Connection, EqAll, ExpressionMethods, Insertable, JoinOnDsl, QueryDsl, QuerySource, RunQueryDsl, Selectable, SelectableHelper, Table};
 use diesel::associations::HasTable;
 use diesel::connection::SimpleConnection;
+use diesel::dsl::{count_star, exists};
 use diesel::result::Error;
+use diesel::sql_types::{Array, BigInt, Bool, Text, Timestamp};
 use diesel::result::Error::DatabaseError;
 use eyre::{bail, OptionExt, Result};
-use fatline_rs::action::LinkAction;
-use fatline_rs::proto::{FidRequest, LinksByFidRequest, LinksByTargetRequest};
+use fatline_rs::proto::{FidRequest, LinksByFidRequest, LinksByTargetRequest, Message, MessageType};
+use fatline_rs::proto::link_body::Target as LinkTarget;
 use fatline_rs::proto::links_by_target_request::Target;
+use fatline_rs::proto::message_data::Body as MBody;
 use fatline_rs::users::{Profile, UserService};
-use fatline_rs::utils::link_from_message;
 use r2d2_postgres::postgres::fallible_iterator::FallibleIterator;
 use tracing::debug;
 use crate::schema;
 
+use crate::schema::link_removals::dsl::link_removals;
+use crate::schema::link_removals::dsl::timestamp as r_timestamp;
 use crate::schema::links::dsl::fid as l_fid;
+use crate::schema::links::dsl::link_type;
 use crate::schema::links::dsl::links;
 use crate::schema::links::dsl::target;
+use crate::schema::links::dsl::timestamp as l_timestamp;
 use crate::schema::notifications::dsl::notifications;
 use crate::schema::users::dsl::fid as u_fid;
 use crate::schema::users::dsl::users;
+use crate::pagination::Cursor;
 use crate::service::ServiceState;
-use crate::user_models::{Link, Notification, User};
+use crate::timeline_repo::{backfill_home_timeline, follow_changed, remove_follows_from_timelines};
+use crate::user_models::{Link, LinkEvent, Notification, User, ViewerContext, ViewerProfile};
+
+pub const FOLLOW_LINK_TYPE: &'static str = "follow";
+const LINK_PAGE_SIZE: u32 = 1000;
+// keeps multi-row inserts well under postgres' bind parameter limit
+const INSERT_CHUNK_SIZE: usize = 1000;
 
 #[derive(Debug, Copy, Clone)]
 pub enum FollowDirection {
@@ -33,14 +46,30 @@ pub enum FollowDirection {
     FollowedBy
 }
 
+/// Set of accounts shared between the viewer and a profile
+#[derive(Debug, Copy, Clone)]
+pub enum ViewerOverlap {
+    /// accounts followed by both the viewer and the profile
+    Mutuals,
+    /// followers of the profile that the viewer follows
+    KnownFollowers
+}
+
 #[async_trait]
 pub trait UserRepository {
     async fn get_user_profile(&self, fid_q: u64, force_fetch: bool) -> Result<Profile>;
     async fn fetch_and_store_profile(&self, fid_q: u64) -> Result<Profile>;
-    async fn get_profile_links(&self, fid_q: u64, force_fetch: bool, direction: FollowDirection) -> Result<Vec<Profile>>;
+    async fn get_profile_links(&self, fid_q: u64, force_fetch: bool, direction: FollowDirection, type_q: Option<&str>) -> Result<Vec<Profile>>;
     async fn get_user_notifications(&self, fid_q: u64) -> Result<Vec<Notification>>;
-    async fn fetch_and_store_links(&self, fid_q: u64, direction: FollowDirection) -> Result<Vec<Profile>>;
+    async fn fetch_and_store_links(&self, fid_q: u64, direction: FollowDirection, type_q: Option<&str>) -> Result<Vec<Profile>>;
+    async fn store_link_event(&self, event: LinkEvent) -> Result<()>;
     async fn fetch_user_latest_notification_type(&self, fid_q: u64) -> Result<i32>;
+    async fn get_viewer_context(&self, viewer: u64, fids_q: &[u64]) -> Result<HashMap<u64, ViewerContext>>;
+    async fn with_viewer_context(&self, viewer: u64, profiles: Vec<Profile>) -> Result<Vec<ViewerProfile>>;
+    async fn get_viewer_overlap(&self, viewer: u64, fid_q: u64, overlap: ViewerOverlap, limit: i64, cursor: Option<Cursor>) -> Result<(Vec<Profile>, Option<Cursor>)>;
+    async fn search_users(&self, viewer: u64, query: &str, boost_following: bool, limit: i64) -> Result<Vec<Profile>>;
+    /// profiles we already have for fids_q, without going to the hub for missing ones
+    async fn get_stored_profiles(&self, fids_q: &[u64]) -> Result<HashMap<u64, Profile>>;
 }
 
 impl Into<User> for Profile {
@@ -69,6 +98,91 @@ impl Into<Profile> for User {
     }
 }
 
+// exact username match, then username prefix, then (optionally) people the viewer follows, then fuzzy score
+const SEARCH_USERS_QUERY: &'static str = r#"
+SELECT u.fid, u.username, u.display_name, u.bio, u.url, u.profile_pic
+FROM users u
+WHERE lower(u.username) LIKE $2 ESCAPE '\'
+   OR u.display_name % $1
+   OR $1 <% u.bio
+ORDER BY
+    lower(u.username) = lower($1) DESC,
+    lower(u.username) LIKE $2 ESCAPE '\' DESC,
+    ($3 AND EXISTS (SELECT 1 FROM links l WHERE l.fid = $4 AND l.target = u.fid AND l.link_type = 'follow')) DESC,
+    greatest(similarity(coalesce(u.display_name, ''), $1), word_similarity($1, coalesce(u.bio, ''))) DESC,
+    u.fid
+LIMIT $5
+"#;
+
+// $1, $2 and $3 line up into the (fid, target, link_type) keys to delete
+const DELETE_LINKS_QUERY: &'static str = r#"
+DELETE FROM links l
+USING unnest($1::int8[], $2::int8[], $3::text[]) AS stale(fid, target, link_type)
+WHERE l.fid = stale.fid AND l.target = stale.target AND l.link_type = stale.link_type
+"#;
+
+// keeps the newest remove for a link
+const STORE_LINK_REMOVAL_QUERY: &'static str = r#"
+INSERT INTO link_removals (fid, target, link_type, timestamp) VALUES ($1, $2, $3, $4)
+ON CONFLICT (fid, target, link_type) DO UPDATE SET timestamp = excluded.timestamp
+WHERE link_removals.timestamp < excluded.timestamp
+"#;
+
+/**
+* parses a LinkAdd / LinkRemove message of any link type
+*/
+pub fn link_event_from_message(message: &Message) -> Option<LinkEvent> {
+    let data = message.data.as_ref()?;
+    let removed = match data.r#type() {
+        MessageType::LinkAdd => false,
+        MessageType::LinkRemove => true,
+        _ => return None
+    };
+    match &data.body {
+        Some(MBody::LinkBody(body)) => match body.target {
+            Some(LinkTarget::TargetFid(target_fid)) => Some(LinkEvent {
+                fid: data.fid,
+                target: target_fid,
+                link_type: body.r#type.clone(),
+                timestamp: data.timestamp,
+                removed,
+            }),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+// users on the other end of fid_q's links, restricted to one link type unless type_q is None
+fn load_linked_users(db: &mut PgConnection, fid_q: u64, direction: FollowDirection, type_q: Option<&str>) -> QueryResult<Vec<User>> {
+    let all_types = type_q.is_none();
+    let type_q = type_q.unwrap_or_default().to_string();
+    match direction {
+        FollowDirection::FollowedBy =>
+            users.select(User::as_select())
+                .filter(
+                    u_fid.eq_any(
+                        links.select(l_fid)
+                            .filter(target.eq(fid_q as i64).and(link_type.eq(type_q).or(all_types)))
+                    )
+                )
+                .load(db),
+        FollowDirection::Following =>
+            users.select(User::as_select())
+                .filter(
+                    u_fid.eq_any(
+                        links.select(target)
+                            .filter(l_fid.eq(fid_q as i64).and(link_type.eq(type_q).or(all_types)))
+                    )
+                )
+                .load(db)
+    }
+}
+
+fn escape_like(query: &str) -> String {
+    query.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
 #[async_trait]
 impl UserRepository for ServiceState {
     async fn get_user_profile(&self, fid_q: u64, force_fetch: bool) -> Result<Profile> {
@@ -107,131 +221,176 @@ impl UserRepository for ServiceState {
         Ok(insert.into())
     }
 
-    async fn get_profile_links(&self, fid_q: u64, force_fetch: bool, direction: FollowDirection) -> Result<Vec<Profile>> {
+    async fn get_profile_links(&self, fid_q: u64, force_fetch: bool, direction: FollowDirection, type_q: Option<&str>) -> Result<Vec<Profile>> {
         if force_fetch {
-            return self.fetch_and_store_links(fid_q, direction).await;
+            return self.fetch_and_store_links(fid_q, direction, type_q).await;
         }
         let existing = {
             let mut db = self.db_pool.get()?;
-            match direction {
-                FollowDirection::FollowedBy =>
-                    users.select(User::as_select())
-                        .filter(
-                            u_fid.eq_any(
-                                links.select(l_fid).filter(target.eq(fid_q as i64))
-                            )
-                        )
-                        .get_results(&mut db),
-                FollowDirection::Following =>
-                    users.select(User::as_select())
-                        .filter(
-                            u_fid.eq_any(
-                                links.select(target).filter(l_fid.eq(fid_q as i64))
-                            )
-                        )
-                        .get_results(&mut db)
-            }
+            load_linked_users(&mut db, fid_q, direction, type_q)
         };
 
         if let Ok(existing_users) = existing {
             return Ok(existing_users.iter().cloned().map(|u| u.into()).collect())
         };
 
-        Ok(self.fetch_and_store_links(fid_q, direction).await?)
+        Ok(self.fetch_and_store_links(fid_q, direction, type_q).await?)
     }
 
-    async fn fetch_and_store_links(&self, fid_q: u64, direction: FollowDirection) -> Result<Vec<Profile>> {
+    async fn fetch_and_store_links(&self, fid_q: u64, direction: FollowDirection, type_q: Option<&str>) -> Result<Vec<Profile>> {
         let mut db = self.db_pool.get()?;
         let mut hub_client = {
             let locked = self.hub_client.lock().await;
             locked.clone()
         };
 
-        let mut to_add = BTreeSet::new();
-        let mut adds = vec![];
-        let mut deletes = vec![];
-
-        let fetched = match direction {
-            FollowDirection::Following => hub_client.get_links_by_fid(LinksByFidRequest {
-                 fid: fid_q,
-                 page_token: None,
-                 reverse: None,
-                 page_size: None,
-                 link_type: Some("follow".to_string()),
-             }).await?.into_inner(),
-            FollowDirection::FollowedBy => hub_client.get_links_by_target(LinksByTargetRequest {
-                link_type: Some("follow".to_string()),
-                page_size: None,
-                reverse: None,
-                page_token: None,
-                target: Some(Target::TargetFid(fid_q))
-            }).await?.into_inner()
-        }.messages.iter().cloned().filter_map(link_from_message)
-            .flatten()
-            .collect::<Vec<_>>();
-
-        for action in fetched {
-            match action {
-                LinkAction::AddFollow(follow) => {
-                    to_add.insert(follow.source_fid as i64);
-                    to_add.insert(follow.target_fid as i64);
-                    adds.push(follow);
-                }
-                LinkAction::RemoveFollow(remove) => {
-                    deletes.push((remove.source_fid, remove.target_fid));
+        // page through everything the hub has, later messages win for the same (source, target, type)
+        let mut current = BTreeMap::new();
+        let mut page_token = None;
+        loop {
+            let response = match direction {
+                FollowDirection::Following => hub_client.get_links_by_fid(LinksByFidRequest {
+                    fid: fid_q,
+                    page_token: page_token.take(),
+                    reverse: None,
+                    page_size: Some(LINK_PAGE_SIZE),
+                    link_type: type_q.map(str::to_string),
+                }).await?.into_inner(),
+                FollowDirection::FollowedBy => hub_client.get_links_by_target(LinksByTargetRequest {
+                    link_type: type_q.map(str::to_string),
+                    page_size: Some(LINK_PAGE_SIZE),
+                    reverse: None,
+                    page_token: page_token.take(),
+                    target: Some(Target::TargetFid(fid_q))
+                }).await?.into_inner()
+            };
+
+            for event in response.messages.iter().filter_map(link_event_from_message) {
+                let key = (event.fid as i64, event.target as i64, event.link_type.clone());
+                if event.removed {
+                    current.remove(&key);
+                } else {
+                    current.insert(key, event.to_link());
                 }
             }
+
+            match response.next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => break
+            }
         }
-        
+
+        let to_add = current.keys()
+            .flat_map(|(s, t, _)| [*s, *t])
+            .collect::<BTreeSet<_>>();
+
+        let adds = current.values().cloned().collect::<Vec<_>>();
+
         db.transaction::<_,eyre::Error,_>(|db| {
 
-            // create our user to ensure key constraints
-            diesel::insert_into(users::table())
-                .values(to_add.iter().copied().map(|f| User::empty(f)).collect::<Vec<_>>())
-                .on_conflict_do_nothing()
-                .execute(db)?;
-
-            diesel::insert_into(links::table())
-                .values(adds.iter().map(|info|{
-                    let timestamp = fatline_rs::utils::fc_timestamp_to_unix(info.timestamp).unwrap_or_default();
-                    let date = DateTime::from_timestamp(timestamp as i64, 0).unwrap_or_default();
-                    Link {
-                        fid: info.source_fid as i64,
-                        target: info.target_fid as i64,
-                        timestamp: SystemTime::from(date),
-                    }
-                }).collect::<Vec<_>>())
-                .on_conflict_do_nothing()
-                .execute(db)?;
-
-            for (s,t) in deletes {
-                diesel::delete(links.filter(
-                    l_fid.eq(s as i64)
-                        .and(target.eq(t as i64))
-                )).execute(db)?;
+            // create our users to ensure key constraints
+            for chunk in to_add.iter().copied().map(User::empty).collect::<Vec<_>>().chunks(INSERT_CHUNK_SIZE) {
+                diesel::insert_into(users::table())
+                    .values(chunk)
+                    .on_conflict_do_nothing()
+                    .execute(db)?;
             }
 
+            // the hub's set is authoritative, drop anything it no longer has
+            let all_types = type_q.is_none();
+            let type_filter = type_q.unwrap_or_default().to_string();
+            let existing: Vec<(i64, i64, String)> = match direction {
+                FollowDirection::Following => links.select((l_fid, target, link_type))
+                    .filter(l_fid.eq(fid_q as i64).and(link_type.eq(type_filter).or(all_types)))
+                    .load(db)?,
+                FollowDirection::FollowedBy => links.select((l_fid, target, link_type))
+                    .filter(target.eq(fid_q as i64).and(link_type.eq(type_filter).or(all_types)))
+                    .load(db)?,
+            };
+            let stale = existing.into_iter().filter(|key| !current.contains_key(key)).collect::<Vec<_>>();
+            if !stale.is_empty() {
+                diesel::sql_query(DELETE_LINKS_QUERY)
+                    .bind::<Array<BigInt>, _>(stale.iter().map(|(s, _, _)| *s).collect::<Vec<_>>())
+                    .bind::<Array<BigInt>, _>(stale.iter().map(|(_, t, _)| *t).collect::<Vec<_>>())
+                    .bind::<Array<Text>, _>(stale.iter().map(|(_, _, kind)| kind.clone()).collect::<Vec<_>>())
+                    .execute(db)?;
+            }
+            let unfollowed = stale.iter()
+                .filter(|(_, _, kind)| kind == FOLLOW_LINK_TYPE)
+                .map(|(s, t, _)| (*s, *t))
+                .collect::<Vec<_>>();
+            remove_follows_from_timelines(db, &unfollowed)?;
+
+            // the other end of each new follow, fid_q is on this end
+            let mut followed = Vec::new();
+            for chunk in adds.chunks(INSERT_CHUNK_SIZE) {
+                let inserted = diesel::insert_into(links::table())
+                    .values(chunk)
+                    .on_conflict_do_nothing()
+                    .returning(Link::as_returning())
+                    .get_results(db)?;
+                followed.extend(inserted.iter().filter(|link| link.link_type == FOLLOW_LINK_TYPE).map(|link| match direction {
+                    FollowDirection::Following => link.target,
+                    FollowDirection::FollowedBy => link.fid,
+                }));
+            }
+            match direction {
+                FollowDirection::Following => backfill_home_timeline(db, &[fid_q as i64], Some(followed.as_slice()))?,
+                FollowDirection::FollowedBy => backfill_home_timeline(db, &followed, Some(&[fid_q as i64]))?,
+            };
+
             Ok(())
         })?;
 
-        Ok(match direction {
-            FollowDirection::FollowedBy =>
-                users.select(User::as_select())
-                    .filter(
-                        u_fid.eq_any(
-                            links.select(l_fid).filter(target.eq(fid_q as i64))
-                        )
-                    )
-                    .load(&mut db),
-            FollowDirection::Following =>
-                users.select(User::as_select())
-                    .filter(
-                        u_fid.eq_any(
-                            links.select(target).filter(l_fid.eq(fid_q as i64))
-                        )
-                    )
-                    .load(&mut db)
-        }.map(|vec| vec.iter().cloned().map(|u|u.into()).collect())?)
+        Ok(load_linked_users(&mut db, fid_q, direction, type_q)
+            .map(|vec| vec.iter().cloned().map(|u|u.into()).collect())?)
+    }
+
+    async fn store_link_event(&self, event: LinkEvent) -> Result<()> {
+        let mut db = self.db_pool.get()?;
+        let link = event.to_link();
+        // events can be applied out of order, the newest message for a link wins and a remove wins a tie
+        db.transaction::<_,eyre::Error,_>(|db| {
+            let is_follow = link.link_type == FOLLOW_LINK_TYPE;
+            let key = (link.fid, link.target, link.link_type.clone());
+            if event.removed {
+                diesel::sql_query(STORE_LINK_REMOVAL_QUERY)
+                    .bind::<BigInt, _>(link.fid)
+                    .bind::<BigInt, _>(link.target)
+                    .bind::<Text, _>(&link.link_type)
+                    .bind::<Timestamp, _>(link.timestamp)
+                    .execute(db)?;
+                let deleted = diesel::delete(links.find(key).filter(l_timestamp.le(link.timestamp))).execute(db)?;
+                if is_follow && deleted > 0 {
+                    follow_changed(db, link.fid, link.target, false)?;
+                }
+            } else {
+                let removed_since = diesel::select(exists(
+                    link_removals.find(key.clone()).filter(r_timestamp.ge(link.timestamp))
+                )).get_result::<bool>(db)?;
+                if removed_since {
+                    return Ok(());
+                }
+                diesel::delete(link_removals.find(key.clone())).execute(db)?;
+                diesel::insert_into(users::table())
+                    .values(vec![User::empty(link.fid), User::empty(link.target)])
+                    .on_conflict_do_nothing()
+                    .execute(db)?;
+                let inserted = diesel::insert_into(links::table())
+                    .values(&link)
+                    .on_conflict_do_nothing()
+                    .execute(db)?;
+                if inserted == 0 {
+                    diesel::update(links.find(key).filter(l_timestamp.lt(link.timestamp)))
+                        .set(l_timestamp.eq(link.timestamp))
+                        .execute(db)?;
+                } else if is_follow {
+                    follow_changed(db, link.fid, link.target, true)?;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(())
     }
 
     async fn get_user_notifications(&self, fid_q: u64) -> Result<Vec<Notification>> {
@@ -250,4 +409,181 @@ impl UserRepository for ServiceState {
         Ok(latest_type)
     }
 
+    async fn get_viewer_context(&self, viewer: u64, fids_q: &[u64]) -> Result<HashMap<u64, ViewerContext>> {
+        let mut db = self.db_pool.get()?;
+        let viewer = viewer as i64;
+        let fids_q = fids_q.iter().map(|f| *f as i64).collect::<Vec<_>>();
+
+        let followed: HashSet<i64> = links.select(target)
+            .filter(l_fid.eq(viewer).and(target.eq_any(&fids_q)))
+            .filter(link_type.eq(FOLLOW_LINK_TYPE))
+            .load::<i64>(&mut db)?
+            .into_iter()
+            .collect();
+
+        let followers: HashSet<i64> = links.select(l_fid)
+            .filter(target.eq(viewer).and(l_fid.eq_any(&fids_q)))
+            .filter(link_type.eq(FOLLOW_LINK_TYPE))
+            .load::<i64>(&mut db)?
+            .into_iter()
+            .collect();
+
+        // followers of each fid that the viewer also follows
+        let viewer_links = alias!(schema::links as viewer_links);
+        let mutuals: HashMap<i64, i64> = links
+            .filter(target.eq_any(&fids_q).and(link_type.eq(FOLLOW_LINK_TYPE)))
+            .filter(l_fid.eq_any(
+                viewer_links.select(viewer_links.field(target))
+                    .filter(viewer_links.field(l_fid).eq(viewer))
+                    .filter(viewer_links.field(link_type).eq(FOLLOW_LINK_TYPE))
+            ))
+            .group_by(target)
+            .select((target, count_star()))
+            .load::<(i64, i64)>(&mut db)?
+            .into_iter()
+            .collect();
+
+        Ok(fids_q.iter().map(|f| (*f as u64, ViewerContext {
+            viewer_follows: followed.contains(f),
+            follows_viewer: followers.contains(f),
+            mutual_followers: mutuals.get(f).copied().unwrap_or_default(),
+        })).collect())
+    }
+
+    async fn with_viewer_context(&self, viewer: u64, profiles: Vec<Profile>) -> Result<Vec<ViewerProfile>> {
+        let fids_q = profiles.iter().map(|p| p.fid).collect::<Vec<_>>();
+        let context = self.get_viewer_context(viewer, &fids_q).await?;
+        Ok(profiles.into_iter().map(|p| {
+            // no relationship to show against yourself
+            let c = if p.fid == viewer { None } else { context.get(&p.fid).copied() };
+            ViewerProfile::new(p, c)
+        }).collect())
+    }
+
+    async fn get_viewer_overlap(&self, viewer: u64, fid_q: u64, overlap: ViewerOverlap, limit: i64, cursor: Option<Cursor>) -> Result<(Vec<Profile>, Option<Cursor>)> {
+        let mut db = self.db_pool.get()?;
+        let viewer_links = alias!(schema::links as viewer_links);
+        let v_fid = viewer_links.field(l_fid);
+        let v_target = viewer_links.field(target);
+        let v_timestamp = viewer_links.field(schema::links::timestamp);
+
+        // everyone the viewer follows, most recently followed first
+        let mut query = users
+            .inner_join(viewer_links.on(v_target.eq(u_fid)))
+            .filter(v_fid.eq(viewer as i64).and(viewer_links.field(link_type).eq(FOLLOW_LINK_TYPE)))
+            .select((User::as_select(), v_timestamp))
+            .order((v_timestamp.desc(), u_fid.desc()))
+            .limit(limit + 1)
+            .into_boxed();
+
+        query = match overlap {
+            ViewerOverlap::Mutuals =>
+                query.filter(u_fid.eq_any(
+                    links.select(target).filter(l_fid.eq(fid_q as i64).and(link_type.eq(FOLLOW_LINK_TYPE)))
+                )),
+            ViewerOverlap::KnownFollowers =>
+                query.filter(u_fid.eq_any(
+                    links.select(l_fid).filter(target.eq(fid_q as i64).and(link_type.eq(FOLLOW_LINK_TYPE)))
+                )),
+        };
+
+        if let Some(cursor) = cursor {
+            let (time, last_fid) = (cursor.time(), cursor.fid());
+            query = query.filter(
+                v_timestamp.lt(time).or(v_timestamp.eq(time).and(u_fid.lt(last_fid)))
+            );
+        }
+
+        let mut rows: Vec<(User, SystemTime)> = query.load(&mut db)?;
+        let next = if rows.len() as i64 > limit {
+            rows.truncate(limit as usize);
+            rows.last().map(|(user, time)| Cursor::for_fid(*time, user.fid))
+        } else {
+            None
+        };
+
+        Ok((rows.into_iter().map(|(user, _)| user.into()).collect(), next))
+    }
+
+    async fn search_users(&self, viewer: u64, query: &str, boost_following: bool, limit: i64) -> Result<Vec<Profile>> {
+        let mut db = self.db_pool.get()?;
+        let prefix = format!("{}%", escape_like(&query.to_lowercase()));
+        let found = diesel::sql_query(SEARCH_USERS_QUERY)
+            .bind::<Text, _>(query)
+            .bind::<Text, _>(prefix)
+            .bind::<Bool, _>(boost_following)
+            .bind::<BigInt, _>(viewer as i64)
+            .bind::<BigInt, _>(limit)
+            .load::<User>(&mut db)?;
+        Ok(found.into_iter().map(|u| u.into()).collect())
+    }
+
+    async fn get_stored_profiles(&self, fids_q: &[u64]) -> Result<HashMap<u64, Profile>> {
+        let mut db = self.db_pool.get()?;
+        let fids = fids_q.iter().map(|fid| *fid as i64).collect::<Vec<_>>();
+        let found = users.select(User::as_select())
+            .filter(u_fid.eq_any(fids))
+            .load::<User>(&mut db)?;
+        Ok(found.into_iter().map(|u| (u.fid as u64, u.into())).collect())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use fatline_rs::proto::{CastAddBody, LinkBody, MessageData};
+
+    use super::*;
+
+    fn link_message(message_type: MessageType, fid: u64, target_fid: Option<u64>) -> Message {
+        Message {
+            data: Some(MessageData {
+                r#type: message_type as i32,
+                fid,
+                timestamp: 1_000,
+                body: Some(MBody::LinkBody(LinkBody {
+                    r#type: FOLLOW_LINK_TYPE.to_string(),
+                    target: target_fid.map(LinkTarget::TargetFid),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn link_event_from_add_and_remove() {
+        let added = link_event_from_message(&link_message(MessageType::LinkAdd, 1, Some(2))).unwrap();
+        assert_eq!(added, LinkEvent {
+            fid: 1,
+            target: 2,
+            link_type: FOLLOW_LINK_TYPE.to_string(),
+            timestamp: 1_000,
+            removed: false,
+        });
+        let removed = link_event_from_message(&link_message(MessageType::LinkRemove, 1, Some(2))).unwrap();
+        assert!(removed.removed);
+    }
+
+    #[test]
+    fn link_event_ignores_other_messages() {
+        assert_eq!(link_event_from_message(&link_message(MessageType::LinkAdd, 1, None)), None);
+        assert_eq!(link_event_from_message(&link_message(MessageType::CastAdd, 1, Some(2))), None);
+
+        let mut cast = link_message(MessageType::LinkAdd, 1, Some(2));
+        cast.data.as_mut().unwrap().body = Some(MBody::CastAddBody(CastAddBody::default()));
+        assert_eq!(link_event_from_message(&cast), None);
+        assert_eq!(link_event_from_message(&Message::default()), None);
+    }
+
+
+    #[test]
+    fn escape_like_escapes_wildcards_and_backslashes() {
+        assert_eq!(escape_like("plain"), "plain");
+        assert_eq!(escape_like("50%_off"), "50\\%\\_off");
+        assert_eq!(escape_like("a\\b"), "a\\\\b");
+        // the backslash added for a wildcard isn't escaped again
+        assert_eq!(escape_like("\\%"), "\\\\\\%");
+    }
 }
@@ -0,0 +1,14 @@
+// Materialized views aren't picked up by diesel print-schema, so they're declared by hand here
+use crate::schema::{links, users};
+
+diesel::table! {
+    follow_suggestions (fid, suggested_fid) {
+        fid -> Int8,
+        suggested_fid -> Int8,
+        overlap -> Int8,
+    }
+}
+
+// pairwise, since links and users are already allowed together in schema.rs
+diesel::allow_tables_to_appear_in_same_query!(follow_suggestions, links);
+diesel::allow_tables_to_appear_in_same_query!(follow_suggestions, users);
@@ -1,175 +1,1018 @@
 use std::any::Any;
+use std::collections::{HashMap, VecDeque};
 use std::future::Future;
 use std::ops::{Add, Deref};
+use std::panic::AssertUnwindSafe;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use crossbeam::atomic::AtomicCell;
-use crossbeam::channel::{Receiver, after};
-use crossbeam::{scope, select};
-use dashmap::DashMap;
-use fatline_rs::posts::CastId;
+use dotenvy::var;
 use futures_util::FutureExt;
 use tokio::join;
-use tokio::sync::Mutex;
-use tracing::{debug, error, Instrument, Level, span, trace, warn};
-use tokio::task::JoinHandle;
-use tokio::time::{Interval, interval};
+use tokio::sync::{Mutex, Semaphore};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::{debug, error, Instrument, Level, span, Span, trace, warn};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{Interval, interval, MissedTickBehavior, sleep};
+use eyre::{bail, eyre, Result};
+use serde::{Deserialize, Serialize};
+use crate::cast_repo::CastRepository;
+use crate::crawl::{CrawlScope, Hop};
+use crate::error::ServerError;
+use crate::last_run::LastRunMap;
+use crate::queue::{LEASE_RENEW_INTERVAL, QueuedTask, TaskQueue};
+use crate::schedule::SharedSchedule;
+use crate::stats::WorkerStats;
 use crate::service::ServiceState;
 use crate::ServiceArcState;
 use crate::signer_repo::SignerRepository;
-use crate::user_models::Signer;
+use crate::cast_models::{CastEvent, ReactionEvent};
+use crate::user_models::{LinkEvent, Signer};
 use crate::user_repo::{FollowDirection, UserRepository};
 
-#[derive(Debug,Hash,Eq,PartialEq,Clone)]
+#[derive(Debug,Hash,Eq,PartialEq,Clone,Serialize,Deserialize)]
 pub enum Task {
     IndexFid(u64, bool),
-    IndexLinks(u64),
+    IndexLinks(u64, bool),
     IndexFidCasts(u64, bool),
-    IndexCast(CastId),
-    UpdateSigner(Signer)
+    /// fid and hash of the cast
+    IndexCast(u64, Vec<u8>),
+    /// parent url of the channel
+    IndexChannel(String),
+    UpdateSigner(Signer),
+    UpdateLink(LinkEvent),
+    UpdateCast(CastEvent),
+    UpdateReaction(ReactionEvent)
+}
+
+/// Interactive tasks are drained first, background tasks get a guaranteed share of what's left
+#[derive(Debug,Hash,Eq,PartialEq,Copy,Clone,Serialize,Deserialize)]
+pub enum Priority {
+    Interactive,
+    Background
+}
+
+impl Priority {
+    pub const ALL: [Priority; 2] = [Priority::Interactive, Priority::Background];
+
+    /// stored in task_queue.priority, lower lanes are leased first
+    pub fn lane(&self) -> i16 {
+        match self {
+            Priority::Interactive => 0,
+            Priority::Background => 1,
+        }
+    }
+
+    pub fn from_lane(lane: i16) -> Self {
+        match lane {
+            0 => Priority::Interactive,
+            _ => Priority::Background
+        }
+    }
+}
+
+/// Why a task was queued
+#[derive(Debug,Hash,Eq,PartialEq,Copy,Clone,Serialize,Deserialize)]
+pub enum Origin {
+    /// a request from a logged in user
+    User,
+    /// an event streamed from the hub
+    Subscriber,
+    /// spawned by another task while indexing
+    Crawl,
+    /// moved back out of the dead letters
+    Replay,
+    /// queued by hand through the admin routes
+    Admin
+}
+
+impl Origin {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Origin::User => "user",
+            Origin::Subscriber => "subscriber",
+            Origin::Crawl => "crawl",
+            Origin::Replay => "replay",
+            Origin::Admin => "admin",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "user" => Origin::User,
+            "subscriber" => Origin::Subscriber,
+            "replay" => Origin::Replay,
+            "admin" => Origin::Admin,
+            _ => Origin::Crawl
+        }
+    }
+
+    pub fn default_priority(&self) -> Priority {
+        match self {
+            Origin::User | Origin::Admin => Priority::Interactive,
+            _ => Priority::Background
+        }
+    }
+}
+
+/// A task along with where it came from, which lane it runs in and how deep into a crawl it is
+#[derive(Debug,Clone)]
+pub struct TaskEnvelope {
+    pub task: Task,
+    pub priority: Priority,
+    pub origin: Origin,
+    pub hop: Hop,
+    /// how long the task waits in the queue before it can run
+    pub delay: Duration,
+}
+
+impl TaskEnvelope {
+    /// starts a new crawl seeded from the task's fid
+    pub fn new(task: Task, origin: Origin) -> Self {
+        let seed = Hop::seed(task.fid());
+        let hop = match task {
+            // the profiles it indexes are one hop out
+            Task::IndexLinks(..) => seed.next(),
+            _ => seed
+        };
+        TaskEnvelope {
+            task,
+            priority: origin.default_priority(),
+            origin,
+            hop,
+            delay: Duration::ZERO
+        }
+    }
+
+    /// a task spawned while indexing, part of the crawl `hop` belongs to
+    pub fn crawl(task: Task, hop: Hop) -> Self {
+        TaskEnvelope {
+            task,
+            priority: Origin::Crawl.default_priority(),
+            origin: Origin::Crawl,
+            hop,
+            delay: Duration::ZERO
+        }
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// Task variant without its arguments, used for per-type limits and reporting
+#[derive(Debug,Hash,Eq,PartialEq,Copy,Clone,Serialize,Deserialize)]
+pub enum TaskKind {
+    IndexFid,
+    IndexLinks,
+    IndexFidCasts,
+    IndexCast,
+    IndexChannel,
+    UpdateSigner,
+    UpdateLink,
+    UpdateCast,
+    UpdateReaction
+}
+
+impl TaskKind {
+    pub const ALL: [TaskKind; 9] = [
+        TaskKind::IndexFid,
+        TaskKind::IndexLinks,
+        TaskKind::IndexFidCasts,
+        TaskKind::IndexCast,
+        TaskKind::IndexChannel,
+        TaskKind::UpdateSigner,
+        TaskKind::UpdateLink,
+        TaskKind::UpdateCast,
+        TaskKind::UpdateReaction,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TaskKind::IndexFid => "index_fid",
+            TaskKind::IndexLinks => "index_links",
+            TaskKind::IndexFidCasts => "index_fid_casts",
+            TaskKind::IndexCast => "index_cast",
+            TaskKind::IndexChannel => "index_channel",
+            TaskKind::UpdateSigner => "update_signer",
+            TaskKind::UpdateLink => "update_link",
+            TaskKind::UpdateCast => "update_cast",
+            TaskKind::UpdateReaction => "update_reaction",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        TaskKind::ALL.iter().find(|kind| kind.name() == name).copied()
+    }
+
+    fn default_limit(&self) -> usize {
+        match self {
+            TaskKind::IndexFid => 4,
+            _ => 2
+        }
+    }
+}
+
+impl Task {
+    /// identity of the task, identical pending tasks are coalesced on this
+    pub fn key(&self) -> String {
+        match self {
+            Task::IndexFid(fid, force) => format!("index_fid:{fid}:{force}"),
+            Task::IndexLinks(fid, force) => format!("index_links:{fid}:{force}"),
+            Task::IndexFidCasts(fid, force) => format!("index_fid_casts:{fid}:{force}"),
+            Task::IndexCast(fid, hash) => format!("index_cast:{fid}:{}", hex::encode(hash)),
+            Task::IndexChannel(url) => format!("index_channel:{url}"),
+            Task::UpdateSigner(signer) => format!("update_signer:{}:{}", hex::encode(&signer.pk), signer.active),
+            Task::UpdateLink(link) => format!(
+                "update_link:{}:{}:{}:{}:{}", link.fid, link.target, link.link_type, link.timestamp, link.removed
+            ),
+            Task::UpdateCast(cast) => format!("update_cast:{}:{}:{}", cast.fid, hex::encode(&cast.hash), cast.removed),
+            Task::UpdateReaction(reaction) => format!(
+                "update_reaction:{}:{}:{}:{}:{}",
+                reaction.fid, hex::encode(&reaction.target_hash), reaction.reaction_type, reaction.timestamp, reaction.removed
+            ),
+        }
+    }
+
+    /// key the last run is recorded under, forced and unforced runs share a throttle
+    pub fn throttle_key(&self) -> String {
+        match self {
+            Task::IndexFid(fid, _) => format!("index_fid:{fid}"),
+            Task::IndexLinks(fid, _) => format!("index_links:{fid}"),
+            Task::IndexFidCasts(fid, _) => format!("index_fid_casts:{fid}"),
+            _ => self.key()
+        }
+    }
+
+    /// whether the task asked to skip its kind's interval
+    pub fn forced(&self) -> bool {
+        match self {
+            Task::IndexFid(_, force) | Task::IndexLinks(_, force) | Task::IndexFidCasts(_, force) => *force,
+            _ => false
+        }
+    }
+
+    /// the fid the task is about
+    pub fn fid(&self) -> u64 {
+        match self {
+            Task::IndexFid(fid, _) => *fid,
+            Task::IndexLinks(fid, _) => *fid,
+            Task::IndexFidCasts(fid, _) => *fid,
+            Task::IndexCast(fid, _) => *fid,
+            // channels aren't anyone's, and a channel index never crawls
+            Task::IndexChannel(_) => 0,
+            Task::UpdateSigner(signer) => signer.fid as u64,
+            Task::UpdateLink(link) => link.fid,
+            Task::UpdateCast(cast) => cast.fid,
+            Task::UpdateReaction(reaction) => reaction.fid,
+        }
+    }
+
+    pub fn kind(&self) -> TaskKind {
+        match self {
+            Task::IndexFid(..) => TaskKind::IndexFid,
+            Task::IndexLinks(..) => TaskKind::IndexLinks,
+            Task::IndexFidCasts(..) => TaskKind::IndexFidCasts,
+            Task::IndexCast(..) => TaskKind::IndexCast,
+            Task::IndexChannel(..) => TaskKind::IndexChannel,
+            Task::UpdateSigner(..) => TaskKind::UpdateSigner,
+            Task::UpdateLink(..) => TaskKind::UpdateLink,
+            Task::UpdateCast(..) => TaskKind::UpdateCast,
+            Task::UpdateReaction(..) => TaskKind::UpdateReaction,
+        }
+    }
+}
+
+/**
+* Concurrency limits for the worker, read from WORKER_CONCURRENCY and WORKER_LIMIT_<KIND> (eg WORKER_LIMIT_INDEX_FID)
+*/
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    pub concurrency: usize,
+    pub kind_limits: HashMap<TaskKind, usize>,
+}
+
+fn env_usize(name: &str) -> Option<usize> {
+    var(name).ok().and_then(|value| usize::from_str(&value).ok())
+}
+
+impl WorkerConfig {
+    pub fn from_env() -> Self {
+        let concurrency = env_usize("WORKER_CONCURRENCY").unwrap_or(8).max(1);
+        let kind_limits = TaskKind::ALL.iter().map(|kind| {
+            let limit = env_usize(&format!("WORKER_LIMIT_{}", kind.name().to_uppercase()))
+                .unwrap_or(kind.default_limit())
+                .clamp(1, concurrency);
+            (*kind, limit)
+        }).collect();
+        WorkerConfig {
+            concurrency,
+            kind_limits
+        }
+    }
 }
 
 pub struct Worker {
-    handle: JoinHandle<()>
+    poll_handle: JoinHandle<()>,
+    consume_handle: JoinHandle<()>,
+    sweep_handle: JoinHandle<()>,
+}
+
+// how many tasks to lease from the queue at once
+const LEASE_BATCH: i64 = 32;
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// how often expired last runs and crawl budgets are swept out
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+pub fn queue_task(queue: &TaskQueue, envelope: TaskEnvelope) {
+    let task = envelope.task.clone();
+    match queue.enqueue(envelope) {
+        Ok(true) => {},
+        Ok(false) => trace!("coalesced {task:?} into a pending task"),
+        Err(e) => match e.downcast_ref::<ServerError>() {
+            Some(ServerError::QueueFull) => warn!("Task queue is full, dropping {task:?}"),
+            _ => error!("Couldn't queue task {task:?} {e}")
+        }
+    }
 }
 
 fn now() -> u64 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
 }
 
-/**
-* returns the time to insert as "latest" and whether to schedule based on the last and gap supplied.
-*/
-fn should_schedule(last: u64, gap: usize) -> (u64, bool) {
-    let now = now();
-    (now, now > Duration::from_secs(last).add(Duration::from_secs(gap as u64)).as_secs())
+/// drops fids outside the crawl scope and whatever the depth limit and budgets don't admit, pairing the rest with their delay
+async fn crawl_targets(state: &ServiceState, hop: Hop, mut fids: Vec<u64>) -> Result<Vec<(u64, Duration)>> {
+    if state.crawler.policy().scope == CrawlScope::LocalUsers {
+        let local = state.signer_fids(fids.clone()).await?;
+        fids.retain(|fid| local.contains(fid));
+    }
+    let delays = state.crawler.admit(hop, fids.len());
+    Ok(fids.into_iter().zip(delays).collect())
 }
 
-async fn index_fid(fid: u64, service_state: Arc<ServiceState>) {
+async fn index_fid(fid: u64, hop: Hop, service_state: Arc<ServiceState>) -> Result<()> {
     match service_state.fetch_and_store_profile(fid).await {
         Ok(p) => {
-            let _= service_state.work_sender.send(Task::IndexLinks(fid));
+            let links_hop = hop.next();
+            for (target, delay) in crawl_targets(&service_state, links_hop, vec![fid]).await? {
+                queue_task(&service_state.task_queue, TaskEnvelope::crawl(Task::IndexLinks(target, false), links_hop).with_delay(delay));
+            }
             debug!("Successfully indexed profile for fid {}", fid);
+            Ok(())
         }
         Err(e) => {
             error!("Error indexing profile {e}");
+            Err(e)
         }
-    };
+    }
 }
 
-async fn index_links(fid: u64, state: Arc<ServiceState>) {
-    match state.fetch_and_store_links(fid,FollowDirection::Following).await {
-        Ok(p) => {
-            for profile in p {
-                let _= state.work_sender.send(Task::IndexFid(profile.fid, false));
+async fn index_links(fid: u64, hop: Hop, state: Arc<ServiceState>) -> Result<()> {
+    let mut failed = false;
+    match state.fetch_and_store_links(fid, FollowDirection::Following, None).await {
+        Ok(p) => match crawl_targets(&state, hop, p.iter().map(|profile| profile.fid).collect()).await {
+            Ok(targets) => {
+                for (target, delay) in targets {
+                    queue_task(&state.task_queue, TaskEnvelope::crawl(Task::IndexFid(target, false), hop).with_delay(delay));
+                }
+                debug!("Successfully indexed following for {fid}");
+            }
+            Err(e) => {
+                error!("Error crawling following of {fid} {e}");
+                failed = true;
             }
-            debug!("Successfully indexed following for {fid}");
+        },
+        Err(e) => {
+            error!("Error indexing following for {fid} {e}");
+            failed = true;
         }
-        Err(_) => {
-            error!("Error indexing following for {fid}");
+    };
+    match state.fetch_and_store_links(fid, FollowDirection::FollowedBy, None).await {
+        Ok(p) => match crawl_targets(&state, hop, p.iter().map(|profile| profile.fid).collect()).await {
+            Ok(targets) => {
+                for (target, delay) in targets {
+                    queue_task(&state.task_queue, TaskEnvelope::crawl(Task::IndexFid(target, false), hop).with_delay(delay));
+                }
+                debug!("Successfully indexed followers for {fid}");
+            }
+            Err(e) => {
+                error!("Error crawling followers of {fid} {e}");
+                failed = true;
+            }
+        },
+        Err(e) => {
+            error!("Error indexing followers for {fid} {e}");
+            failed = true;
         }
     };
-    match state.fetch_and_store_links(fid, FollowDirection::FollowedBy).await {
-        Ok(p) => {
-            for profile in p {
-                let _= state.work_sender.send(Task::IndexFid(profile.fid, false));
+    if failed {
+        bail!("Couldn't index links for {fid}");
+    }
+    Ok(())
+}
+
+async fn index_fid_casts(fid: u64, force: bool, state: Arc<ServiceState>) -> Result<()> {
+    match state.fetch_and_store_casts(fid, force).await {
+        Ok(count) => debug!("Successfully indexed {count} casts for {fid}"),
+        Err(e) => {
+            error!("Error indexing casts for {fid} {e}");
+            return Err(e);
+        }
+    }
+    match state.fetch_and_store_recasts(fid, force).await {
+        Ok(missing) => {
+            // recasts only show up in timelines once the cast itself is stored
+            for (cast_fid, cast_hash) in missing {
+                if let Err(e) = state.fetch_and_store_cast(cast_fid, cast_hash).await {
+                    debug!("Couldn't fetch recasted cast by {cast_fid} {e}");
+                }
             }
-            debug!("Successfully indexed followers for {fid}");
+            debug!("Successfully indexed recasts for {fid}");
+            Ok(())
         }
-        Err(_) => {
-            error!("Error indexing followers for {fid}");
+        Err(e) => {
+            error!("Error indexing recasts for {fid} {e}");
+            Err(e)
         }
-    };
+    }
+}
+
+// how far up a reply chain IndexCast walks looking for the root
+const MAX_ANCESTORS: usize = 64;
+// levels of replies below the indexed cast, and how many replies in total
+const REPLY_DEPTH: usize = 3;
+const REPLY_LIMIT: usize = 500;
+// newest casts pulled per channel index, older ones come in as their authors are indexed
+const CHANNEL_CAST_LIMIT: usize = 500;
+
+/**
+* fetches replies below root breadth first, so a wide thread spends the budget on the closest replies.
+* Stops after REPLY_DEPTH levels or REPLY_LIMIT replies, returns how many were fetched
+*/
+async fn walk_replies<F, Fut>(root: (i64, Vec<u8>), mut fetch_replies: F) -> Result<usize>
+where
+    F: FnMut(i64, Vec<u8>, usize) -> Fut,
+    Fut: Future<Output = Result<Vec<(i64, Vec<u8>)>>>,
+{
+    let mut level = vec![root];
+    let mut fetched = 0;
+    for _ in 0..REPLY_DEPTH {
+        let mut next_level = Vec::new();
+        for (parent_fid, parent_hash) in level {
+            if fetched >= REPLY_LIMIT {
+                break;
+            }
+            let replies = fetch_replies(parent_fid, parent_hash, REPLY_LIMIT - fetched).await?;
+            fetched += replies.len();
+            next_level.extend(replies);
+        }
+        if next_level.is_empty() || fetched >= REPLY_LIMIT {
+            break;
+        }
+        level = next_level;
+    }
+    Ok(fetched)
+}
+
+async fn index_cast(fid: u64, hash: Vec<u8>, state: Arc<ServiceState>) -> Result<()> {
+    let cast = state.fetch_and_store_cast(fid, hash).await.map_err(|e| {
+        error!("Error indexing cast {e}");
+        e
+    })?;
+
+    // reactions from before the subscriber saw the cast are only on the hub
+    match state.fetch_and_store_cast_reactions(fid, cast.hash.clone()).await {
+        Ok(reactions) => debug!("Stored {reactions} new reactions on cast {}", hex::encode(&cast.hash)),
+        Err(e) => warn!("Couldn't sync reactions on cast {} {e}", hex::encode(&cast.hash))
+    }
+
+    // walk up to the root, anything we've already stored doesn't need the hub
+    let mut parent = cast.parent_fid.zip(cast.parent_hash.clone());
+    let mut ancestors = 0;
+    while let Some((parent_fid, parent_hash)) = parent.take() {
+        if ancestors == MAX_ANCESTORS {
+            warn!("Stopped walking up from cast {} after {MAX_ANCESTORS} ancestors", hex::encode(&cast.hash));
+            break;
+        }
+        ancestors += 1;
+        let stored = match state.get_cast(parent_hash.clone()).await? {
+            Some(stored) => stored,
+            None => match state.fetch_and_store_cast(parent_fid as u64, parent_hash.clone()).await {
+                Ok(fetched) => fetched,
+                Err(e) => {
+                    // deleted or never made it to this hub, the thread starts below it
+                    debug!("Couldn't fetch ancestor {} {e}", hex::encode(&parent_hash));
+                    break;
+                }
+            }
+        };
+        parent = stored.parent_fid.zip(stored.parent_hash);
+    }
+
+    let fetched = walk_replies((cast.fid, cast.hash), |parent_fid, parent_hash, limit| {
+        let state = state.clone();
+        async move {
+            let replies = state.fetch_and_store_replies(parent_fid as u64, parent_hash, limit).await?;
+            Ok(replies.into_iter().map(|reply| (reply.fid, reply.hash)).collect())
+        }
+    }).await?;
+    debug!("Successfully indexed cast {fid}, {ancestors} ancestors and {fetched} replies");
+    Ok(())
 }
 
-async fn handle_signer_event(signer: Signer, service_state: Arc<ServiceState>) {
+async fn handle_signer_event(signer: Signer, service_state: Arc<ServiceState>) -> Result<()> {
     let insert_result = service_state.insert_signer(signer).await;
     match insert_result {
         Ok(r) => {
             // successfully stored signer
             debug!("Successfully stored signer for fid {}", r.fid);
+            Ok(())
         },
         Err(e) => {
             error!("Error saving signer {e}");
+            Err(e)
+        }
+    }
+}
+
+async fn handle_cast_event(event: CastEvent, service_state: Arc<ServiceState>) -> Result<()> {
+    let stored = match (event.removed, event.cast.clone()) {
+        (true, _) => service_state.delete_cast(event.fid, event.hash.clone()).await,
+        (false, Some(cast)) => service_state.store_cast(cast).await.map(|_| ()),
+        // queued before events carried the cast
+        (false, None) => service_state.fetch_and_store_cast(event.fid, event.hash.clone()).await.map(|_| ()),
+    };
+    match stored {
+        Ok(_) => {
+            debug!("Successfully stored cast event {} for {}", hex::encode(&event.hash), event.fid);
+            Ok(())
+        }
+        Err(e) => {
+            error!("Error saving cast event {e}");
+            Err(e)
         }
     }
 }
 
-const ONE_MINUTE: usize = 60;
+async fn handle_reaction_event(event: ReactionEvent, service_state: Arc<ServiceState>) -> Result<()> {
+    match service_state.store_reaction_event(event.clone()).await {
+        Ok(_) => {
+            debug!("Successfully stored reaction {} -> {}", event.fid, hex::encode(&event.target_hash));
+            Ok(())
+        }
+        Err(e) => {
+            error!("Error saving reaction {e}");
+            Err(e)
+        }
+    }
+}
+
+async fn handle_link_event(event: LinkEvent, service_state: Arc<ServiceState>) -> Result<()> {
+    match service_state.store_link_event(event.clone()).await {
+        Ok(_) => {
+            debug!("Successfully stored {} link {} -> {}", event.link_type, event.fid, event.target);
+            Ok(())
+        },
+        Err(e) => {
+            error!("Error saving link {e}");
+            Err(e)
+        }
+    }
+}
+
+// failures are returned so the queue can retry them, last runs are only recorded on success
+async fn schedule_task(task: Task, hop: Hop, service_state: Arc<ServiceState>, index_map: Arc<LastRunMap>, schedule: SharedSchedule) -> Result<()> {
+    let kind_schedule = schedule.read().unwrap().get(task.kind());
+    // unthrottled kinds don't need a last run looked up or recorded
+    let last_call = if kind_schedule.throttled() {
+        index_map.get(&task.throttle_key()).unwrap_or(0)
+    } else {
+        0
+    };
+    let now = now();
+    if !kind_schedule.should_run(&task, last_call, now) {
+        trace!("skipping task {:?}", &task);
+        return Ok(());
+    }
 
-async fn schedule_task(task: Task, service_state: Arc<ServiceState>, index_map: Arc<DashMap<Task, u64>>, last: Option<u64>) {
-    let last_call = last.unwrap_or(0);
     let db_conns = service_state.db_pool.state().connections;
     debug!("scheduling task {task:?}, last_call was {last_call}, db conns: {}", db_conns);
     match task.clone() {
         Task::UpdateSigner(signer_event) => {
             trace!("kicking off signer event for {:?}", signer_event.fid);
-            handle_signer_event(signer_event.clone(), service_state.clone()).await;
+            handle_signer_event(signer_event.clone(), service_state.clone()).await?;
         },
-        Task::IndexFid(fid, force) => {
-            // do check
-            let (now, should_schedule) = should_schedule(last_call, ONE_MINUTE * 5);
-            if should_schedule || force {
-                // kick off job
-                trace!("kicking off index for profile on {fid}");
-                index_map.insert(task, now);
-                index_fid(fid.to_owned(), service_state.clone()).await;
-            } else {
-                trace!("skipping task {:?}", &task);
-            }
+        Task::UpdateLink(link_event) => {
+            trace!("kicking off link event for {:?}", link_event.fid);
+            handle_link_event(link_event, service_state.clone()).await?;
         },
-        Task::IndexLinks(fid) => {
-            let (now, should_schedule) = should_schedule(last_call, ONE_MINUTE * 30);
-            if should_schedule {
-                trace!("kicking off index for links on {fid}");
-                index_map.insert(task, now);
-                index_links(fid.to_owned(), service_state.clone()).await;
-            } else {
-                trace!("skipping task {:?}", &task);
-            }
+        Task::UpdateCast(cast_event) => {
+            trace!("kicking off cast event for {:?}", cast_event.fid);
+            handle_cast_event(cast_event, service_state.clone()).await?;
+        },
+        Task::UpdateReaction(reaction_event) => {
+            trace!("kicking off reaction event for {:?}", reaction_event.fid);
+            handle_reaction_event(reaction_event, service_state.clone()).await?;
+        },
+        Task::IndexFid(fid, _) => {
+            trace!("kicking off index for profile on {fid}");
+            index_fid(fid, hop, service_state.clone()).await?;
+        },
+        Task::IndexLinks(fid, _) => {
+            trace!("kicking off index for links on {fid}");
+            index_links(fid, hop, service_state.clone()).await?;
         }
         Task::IndexFidCasts(fid, force) => {
+            trace!("kicking off index for casts on {fid}");
+            index_fid_casts(fid, force, service_state.clone()).await?;
+        }
+        Task::IndexCast(cast_fid, cast_hash) => {
+            trace!("kicking off index for cast {} by {cast_fid}", hex::encode(&cast_hash));
+            index_cast(cast_fid, cast_hash, service_state.clone()).await?;
+        }
+        Task::IndexChannel(url) => {
+            trace!("kicking off index for channel {url}");
+            let stored = service_state.fetch_and_store_channel_casts(url.clone(), CHANNEL_CAST_LIMIT).await?;
+            debug!("Successfully indexed {stored} casts in channel {url}");
+        }
+    }
+    if kind_schedule.throttled() {
+        index_map.record(task.throttle_key(), now);
+    }
+    Ok(())
+}
+
+async fn poll_queue(service_state: Arc<ServiceState>, sender: Sender<QueuedTask>, lease_slots: Arc<Semaphore>) {
+    debug!("Starting queue poller");
+    while !sender.is_closed() {
+        // only lease what the consumer has room to hold, it renews the leases of everything it holds
+        let capacity = lease_slots.available_permits().min(LEASE_BATCH as usize);
+        if capacity == 0 {
+            sleep(POLL_INTERVAL).await;
+            continue;
+        }
+        match service_state.task_queue.lease(capacity as i64) {
+            Ok(leased) if leased.is_empty() => {
+                // leases that keep expiring mean the task is killing its worker
+                match service_state.task_queue.dead_letter_exhausted() {
+                    Ok(0) => {},
+                    Ok(moved) => warn!("Moved {moved} tasks that never completed to dead letters"),
+                    Err(e) => error!("Error dead lettering exhausted tasks {e}")
+                }
+                sleep(POLL_INTERVAL).await;
+            }
+            Ok(leased) => {
+                // slots are handed back by the consumer as it finishes tasks
+                if let Ok(slots) = lease_slots.try_acquire_many(leased.len() as u32) {
+                    slots.forget();
+                }
+                for queued in leased {
+                    let sent = match sender.try_send(queued) {
+                        Ok(_) => Ok(()),
+                        // consumer is behind, wait for room instead of leasing more
+                        Err(TrySendError::Full(queued)) => sender.send(queued).await.map_err(|_| ()),
+                        Err(TrySendError::Closed(_)) => Err(()),
+                    };
+                    if sent.is_err() {
+                        debug!("Consumer gone, stopping poller");
+                        return;
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Error leasing tasks {e}");
+                sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+// a busy queue never comes up empty, so expired state is swept on its own schedule
+async fn sweep_expired(index_map: Arc<LastRunMap>, service_state: Arc<ServiceState>) {
+    let mut ticker = interval(SWEEP_INTERVAL);
+    loop {
+        ticker.tick().await;
+        index_map.evict();
+        service_state.crawler.evict();
+    }
+}
+
+// every this many dispatch rounds background work gets first pick of the free permits
+const BACKGROUND_TURN: usize = 4;
 
+// tasks waiting on a permit, per lane and kind so a burst of one kind can't hold up the others
+struct Dispatcher {
+    service_state: Arc<ServiceState>,
+    index_map: Arc<LastRunMap>,
+    schedule: SharedSchedule,
+    stats: Arc<WorkerStats>,
+    global: Arc<Semaphore>,
+    kind_limits: HashMap<TaskKind, Arc<Semaphore>>,
+    pending: HashMap<(Priority, TaskKind), VecDeque<QueuedTask>>,
+    rounds: usize,
+    /// running tasks and their ids
+    in_flight: HashMap<Task, i64>,
+    running: JoinSet<(QueuedTask, Result<()>)>,
+    lease_slots: Arc<Semaphore>,
+    span: Span,
+}
+
+impl Dispatcher {
+    fn backlog(&self) -> usize {
+        self.pending.values().map(|queue| queue.len()).sum()
+    }
+
+    fn push(&mut self, queued: QueuedTask) {
+        self.pending.entry((queued.priority, queued.task.kind())).or_default().push_back(queued);
+    }
+
+    // everything leased and not finished yet, whether it's running or waiting on a permit
+    fn held(&self) -> Vec<i64> {
+        self.pending.values().flatten().map(|queued| queued.task_id)
+            .chain(self.in_flight.values().copied())
+            .collect()
+    }
+
+    fn renew_leases(&self) {
+        match self.service_state.task_queue.renew(&self.held()) {
+            Ok(renewed) => trace!("renewed {renewed} leases"),
+            Err(e) => error!("Couldn't renew task leases {e}")
+        }
+    }
+
+    fn finish(&mut self, queued: QueuedTask, result: Result<()>) {
+        self.in_flight.remove(&queued.task);
+        self.lease_slots.add_permits(1);
+        let task_id = queued.task_id;
+        let finished = match result {
+            Ok(_) => {
+                self.stats.completed(task_id);
+                self.service_state.task_queue.complete(task_id)
+            }
+            Err(e) => {
+                let reason = e.to_string();
+                self.stats.failed(&queued, &reason);
+                self.service_state.task_queue.fail(queued, &reason)
+            }
+        };
+        if let Err(e) = finished {
+            error!("Couldn't finish task {task_id} {e}");
+        }
+    }
+
+    // start as many pending tasks as the global and per-kind limits allow, interactive lane first
+    fn dispatch(&mut self) {
+        self.rounds = self.rounds.wrapping_add(1);
+        let mut lanes = Priority::ALL;
+        if self.rounds % BACKGROUND_TURN == 0 {
+            // keeps a steady stream of interactive work from starving the crawl
+            lanes.reverse();
+        }
+        for priority in lanes {
+            for kind in TaskKind::ALL {
+                if !self.dispatch_lane((priority, kind)) {
+                    return;
+                }
+            }
         }
-        Task::IndexCast(cast_id) => {
+    }
+
+    // false once the global limit is used up
+    fn dispatch_lane(&mut self, lane: (Priority, TaskKind)) -> bool {
+        let kind = lane.1;
+        loop {
+            let Some(next) = self.pending.get(&lane).and_then(|queue| queue.front()) else { break };
 
+            if self.in_flight.contains_key(&next.task) {
+                // identical task already running, it covers this one
+                let duplicate = self.pending.get_mut(&lane).and_then(|queue| queue.pop_front()).unwrap();
+                trace!("dropping duplicate of in-flight task {:?}", duplicate.task);
+                self.lease_slots.add_permits(1);
+                if let Err(e) = self.service_state.task_queue.complete(duplicate.task_id) {
+                    error!("Couldn't complete task {} {e}", duplicate.task_id);
+                }
+                continue;
+            }
+
+            let Ok(kind_permit) = self.kind_limits[&kind].clone().try_acquire_owned() else { break };
+            let Ok(global_permit) = self.global.clone().try_acquire_owned() else { return false };
+
+            let queued = self.pending.get_mut(&lane).and_then(|queue| queue.pop_front()).unwrap();
+            self.in_flight.insert(queued.task.clone(), queued.task_id);
+            self.stats.started(&queued);
+
+            let service_state = self.service_state.clone();
+            let index_map = self.index_map.clone();
+            let schedule = self.schedule.clone();
+            let span = self.span.clone();
+            self.running.spawn(async move {
+                let run = schedule_task(queued.task.clone(), queued.hop, service_state, index_map, schedule).instrument(span);
+                // hand the task back even if it panicked so it's never stuck in flight
+                let result = AssertUnwindSafe(run).catch_unwind().await.unwrap_or_else(|_| {
+                    error!("Task {:?} panicked", queued.task);
+                    Err(eyre!("task panicked"))
+                });
+                drop((kind_permit, global_permit));
+                (queued, result)
+            });
         }
+        true
     }
 }
 
-async fn consume_receiver(service_state: Arc<ServiceState>, receiver: Receiver<Task>, index_map: Arc<DashMap<Task, u64>>) {
-    debug!("Starting consumer");
-    let span = span!(Level::DEBUG, "worker loop");
+async fn consume_receiver(service_state: Arc<ServiceState>, mut receiver: Receiver<QueuedTask>, lease_slots: Arc<Semaphore>, index_map: Arc<LastRunMap>, schedule: SharedSchedule, stats: Arc<WorkerStats>, config: WorkerConfig) {
+    debug!("Starting consumer with {config:?}");
+    let mut dispatcher = Dispatcher {
+        service_state,
+        index_map,
+        schedule,
+        stats,
+        global: Arc::new(Semaphore::new(config.concurrency)),
+        kind_limits: config.kind_limits.iter()
+            .map(|(kind, limit)| (*kind, Arc::new(Semaphore::new(*limit))))
+            .collect(),
+        pending: HashMap::new(),
+        rounds: 0,
+        in_flight: HashMap::new(),
+        running: JoinSet::new(),
+        lease_slots,
+        span: span!(Level::DEBUG, "worker loop"),
+    };
+
+    let mut renew = interval(LEASE_RENEW_INTERVAL);
+    renew.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut closed = false;
     loop {
-        select! {
-            recv(receiver)->next => {
+        dispatcher.dispatch();
+        tokio::select! {
+            next = receiver.recv(), if !closed => {
                 match next {
-                    Ok(task) => {
-                        let option = index_map.get(&task).map(|task| task.value().clone());
-                        schedule_task(task, service_state.clone(), index_map.clone(), option)
-                        .instrument(span.clone())
-                        .await;
-                    },
-                    Err( e) => {
-                        error!("Error receiving task from worker {:?}", e);
+                    Some(queued) => dispatcher.push(queued),
+                    None => {
+                        debug!("Task channel closed, draining {} pending tasks", dispatcher.backlog());
+                        closed = true;
                     }
                 }
             },
+            Some(done) = dispatcher.running.join_next() => {
+                match done {
+                    Ok((queued, result)) => dispatcher.finish(queued, result),
+                    Err(e) => {
+                        error!("Error joining task {e}");
+                        dispatcher.lease_slots.add_permits(1);
+                    }
+                }
+            },
+            _ = renew.tick(), if !closed || dispatcher.backlog() > 0 || !dispatcher.in_flight.is_empty() => {
+                dispatcher.renew_leases();
+            },
+            else => break,
         }
     }
+    debug!("Consumer stopped");
 }
 
 impl Worker {
 
-    pub fn new(service_state: Arc<ServiceState>, receiver: Receiver<Task>, index_map: Arc<DashMap<Task,u64>>) -> Self {
-        let handle = tokio::spawn(consume_receiver(service_state, receiver, index_map));
+    pub fn new(service_state: Arc<ServiceState>, index_map: Arc<LastRunMap>, schedule: SharedSchedule, stats: Arc<WorkerStats>, config: WorkerConfig) -> Self {
+        let (sender, receiver) = channel(LEASE_BATCH as usize);
+        // running tasks plus a backlog of four per permit waiting to start
+        let lease_slots = Arc::new(Semaphore::new(config.concurrency * 5));
+        let poll_handle = tokio::spawn(poll_queue(service_state.clone(), sender, lease_slots.clone()));
+        let sweep_handle = tokio::spawn(sweep_expired(index_map.clone(), service_state.clone()));
+        let consume_handle = tokio::spawn(consume_receiver(service_state, receiver, lease_slots, index_map, schedule, stats, config));
         Worker {
-            handle
+            poll_handle,
+            consume_handle,
+            sweep_handle
+        }
+    }
+
+    /// stops leasing new tasks and waits for the consumer to finish what it already has
+    pub async fn shutdown(self) {
+        self.poll_handle.abort();
+        self.sweep_handle.abort();
+        if let Err(e) = self.consume_handle.await {
+            error!("Worker consumer didn't stop cleanly {e}");
         }
     }
 
-    pub fn cancel(&self) {
-        self.handle.abort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_keys_include_force() {
+        assert_eq!(Task::IndexFid(3, true).key(), "index_fid:3:true");
+        assert_eq!(Task::IndexLinks(3, false).key(), "index_links:3:false");
+        assert_eq!(Task::IndexFidCasts(3, true).key(), "index_fid_casts:3:true");
+        assert_ne!(Task::IndexFid(3, true).key(), Task::IndexFid(3, false).key());
     }
 
+    #[test]
+    fn forced_and_unforced_runs_share_a_throttle() {
+        for (forced, unforced) in [
+            (Task::IndexFid(3, true), Task::IndexFid(3, false)),
+            (Task::IndexLinks(3, true), Task::IndexLinks(3, false)),
+            (Task::IndexFidCasts(3, true), Task::IndexFidCasts(3, false)),
+        ] {
+            assert_eq!(forced.throttle_key(), unforced.throttle_key());
+        }
+        assert_eq!(Task::IndexFid(3, true).throttle_key(), "index_fid:3");
+        assert_ne!(Task::IndexFid(3, false).throttle_key(), Task::IndexLinks(3, false).throttle_key());
+    }
+
+    #[test]
+    fn event_keys_identify_the_event() {
+        let cast = Task::IndexCast(3, vec![0xab, 0x01]);
+        assert_eq!(cast.key(), "index_cast:3:ab01");
+        assert_eq!(cast.throttle_key(), cast.key());
+        assert_eq!(Task::IndexChannel("chain://x".to_string()).key(), "index_channel:chain://x");
+
+        let link = LinkEvent { fid: 1, target: 2, link_type: "follow".to_string(), timestamp: 9, removed: true };
+        assert_eq!(Task::UpdateLink(link).key(), "update_link:1:2:follow:9:true");
+
+        let cast_event = CastEvent { fid: 1, hash: vec![0xff], removed: false, cast: None };
+        assert_eq!(Task::UpdateCast(cast_event).key(), "update_cast:1:ff:false");
+
+        let reaction = ReactionEvent { fid: 1, target_fid: 2, target_hash: vec![0x0a], reaction_type: 2, timestamp: 9, removed: false };
+        let task = Task::UpdateReaction(reaction);
+        assert_eq!(task.key(), "update_reaction:1:0a:2:9:false");
+        assert_eq!(task.throttle_key(), task.key());
+    }
+
+    #[test]
+    fn lanes_and_origins_round_trip() {
+        for priority in [Priority::Interactive, Priority::Background] {
+            assert_eq!(Priority::from_lane(priority.lane()), priority);
+        }
+        for origin in [Origin::User, Origin::Subscriber, Origin::Crawl, Origin::Replay, Origin::Admin] {
+            assert_eq!(Origin::from_name(origin.name()), origin);
+        }
+        assert!(Priority::Interactive.lane() < Priority::Background.lane());
+        assert_eq!(Origin::User.default_priority(), Priority::Interactive);
+        assert_eq!(Origin::Admin.default_priority(), Priority::Interactive);
+        assert_eq!(Origin::Subscriber.default_priority(), Priority::Background);
+    }
+
+    // every cast gets `width` replies, hashes are the path down from the root
+    async fn walk_tree(width: usize) -> (usize, Vec<(usize, usize)>) {
+        let calls = std::sync::Mutex::new(Vec::new());
+        let fetched = walk_replies((1, vec![]), |_, parent_hash, limit| {
+            calls.lock().unwrap().push((parent_hash.len(), limit));
+            let replies = (0..width.min(limit))
+                .map(|i| {
+                    let mut hash = parent_hash.clone();
+                    hash.push(i as u8);
+                    (1, hash)
+                })
+                .collect();
+            async move { Ok(replies) }
+        }).await.unwrap();
+        (fetched, calls.into_inner().unwrap())
+    }
+
+    #[tokio::test]
+    async fn replies_stop_at_max_depth() {
+        let (fetched, calls) = walk_tree(2).await;
+        assert_eq!(fetched, 2 + 4 + 8);
+        // the deepest level's replies are never asked for their own replies
+        assert!(calls.iter().all(|(depth, _)| *depth < REPLY_DEPTH));
+        assert_eq!(calls.len(), 1 + 2 + 4);
+    }
+
+    #[tokio::test]
+    async fn replies_stop_at_the_limit() {
+        let (fetched, calls) = walk_tree(REPLY_LIMIT).await;
+        assert_eq!(fetched, REPLY_LIMIT);
+        assert_eq!(calls, vec![(0, REPLY_LIMIT)]);
+
+        let (fetched, calls) = walk_tree(300).await;
+        assert_eq!(fetched, REPLY_LIMIT);
+        // the second level only gets what's left of the budget
+        assert_eq!(calls, vec![(0, REPLY_LIMIT), (1, REPLY_LIMIT - 300)]);
+    }
+
+    #[test]
+    fn kind_names_prefix_keys() {
+        let tasks = [
+            Task::IndexFid(1, false),
+            Task::IndexLinks(1, false),
+            Task::IndexFidCasts(1, false),
+            Task::IndexCast(1, vec![1]),
+            Task::IndexChannel("chain://x".to_string()),
+        ];
+        for task in tasks {
+            assert!(task.key().starts_with(&format!("{}:", task.kind().name())), "{}", task.key());
+        }
+    }
 }